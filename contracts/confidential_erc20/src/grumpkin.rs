@@ -0,0 +1,76 @@
+//!
+//! Minimal Grumpkin curve arithmetic (y^2 = x^3 - 17 over the BN254 scalar field).
+//!
+//! Only point addition is implemented: it is all the contract needs to homomorphically
+//! accumulate incoming ciphertexts that no single prover can see together. Everything
+//! else (encryption, range checks, subtraction) stays inside the Noir circuits.
+//!
+//! The point at infinity is represented as all-zero coordinates, which is never a
+//! valid affine point on this curve (0 != -17).
+//!
+
+use stylus_sdk::alloy_primitives::U256;
+
+use crate::{Ciphertext, Point};
+
+/// BN254 scalar field modulus (Grumpkin base field)
+pub const MODULUS: U256 = U256::from_limbs([
+    0x43e1f593f0000001,
+    0x2833e84879b97091,
+    0xb85045b68181585d,
+    0x30644e72e131a029,
+]);
+
+fn add(a: U256, b: U256) -> U256 {
+    a.add_mod(b, MODULUS)
+}
+
+fn sub(a: U256, b: U256) -> U256 {
+    a.add_mod(MODULUS - b, MODULUS)
+}
+
+fn mul(a: U256, b: U256) -> U256 {
+    a.mul_mod(b, MODULUS)
+}
+
+fn div(a: U256, b: U256) -> U256 {
+    // `b` is never zero here: callers handle the vertical-line cases first
+    mul(a, b.inv_mod(MODULUS).unwrap_or_default())
+}
+
+/// Affine point addition, handling doubling and the point at infinity.
+pub fn add_points(p: &Point, q: &Point) -> Point {
+    if p.is_infinity() {
+        return q.clone();
+    }
+    if q.is_infinity() {
+        return p.clone();
+    }
+
+    let (x1, y1) = (U256::from_be_bytes(p.x), U256::from_be_bytes(p.y));
+    let (x2, y2) = (U256::from_be_bytes(q.x), U256::from_be_bytes(q.y));
+
+    let lambda = if x1 == x2 {
+        // P + (-P) = O, and doubling a point with y = 0 also gives O
+        if add(y1, y2).is_zero() {
+            return Point::zero();
+        }
+        // Doubling: lambda = 3x^2 / 2y (curve has a = 0)
+        div(mul(U256::from(3), mul(x1, x1)), add(y1, y1))
+    } else {
+        div(sub(y2, y1), sub(x2, x1))
+    };
+
+    let x3 = sub(sub(mul(lambda, lambda), x1), x2);
+    let y3 = sub(mul(lambda, sub(x1, x3)), y1);
+
+    Point { x: x3.to_be_bytes(), y: y3.to_be_bytes() }
+}
+
+/// Homomorphic ElGamal addition: Enc(a) + Enc(b) = Enc(a + b) under the same key.
+pub fn add_ciphertexts(a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
+    Ciphertext {
+        x1: add_points(&a.x1, &b.x1),
+        x2: add_points(&a.x2, &b.x2),
+    }
+}
@@ -11,28 +11,33 @@
 //!   - stores ciphertexts (as raw bytes),
 //!   - verifies Noir proofs with domain separation,
 //!   - updates balances using NEW ciphertexts provided by the proof,
+//!   - accumulates incoming transfers into a per-user pending ciphertext
+//!     (the only on-chain curve operation, see `grumpkin`),
 //!   - manages ERC-20 custody (deposit/withdraw).
 //!
 
 // Allow `cargo stylus export-abi` to generate a main function.
 #![cfg_attr(not(any(test, feature = "export-abi")), no_main)]
 #![cfg_attr(not(any(test, feature = "export-abi")), no_std)]
+// The ABI export chains one iterator per public method and error, which exceeds the default depth
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate alloc;
 
 use alloc::vec::Vec;
-use stylus_sdk::evm;
 use stylus_sdk::{
     prelude::*,
     abi::Bytes as AbiBytes,
-    call::RawCall,
-    alloy_primitives::{Address, FixedBytes, U256, Bytes},
+    alloy_primitives::{b256, keccak256, Address, FixedBytes, U256, U8, U32, Bytes},
     alloy_sol_types::{sol, SolCall},
+    stylus_core::calls::context::Call,
 };
 use core::str::FromStr;
 
-#[derive(PartialEq, Eq)]
+mod grumpkin;
+
+#[derive(PartialEq, Eq, Clone)]
 pub struct Point {
     pub x: [u8; 32],
     pub y: [u8; 32],
@@ -46,10 +51,15 @@ impl Point {
     pub fn from_bytes(bytes: [u8; 64]) -> Self {
         Self { x: bytes[..32].try_into().unwrap(), y: bytes[32..64].try_into().unwrap() }
     }
+
+    /// All-zero coordinates encode the point at infinity
+    pub fn is_infinity(&self) -> bool {
+        self.x == [0u8; 32] && self.y == [0u8; 32]
+    }
 }
 
 // Ciphertext representation (ElGamal over BabyJub, treated as opaque bytes)
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct Ciphertext {
     pub x1: Point,
     pub x2: Point,
@@ -59,6 +69,15 @@ impl Ciphertext {
     pub fn zero() -> Self {
         Self { x1: Point::zero(), x2: Point::zero() }
     }
+
+    pub fn to_bytes(&self) -> [u8; 128] {
+        let mut out = [0u8; 128];
+        out[0..32].copy_from_slice(&self.x1.x);
+        out[32..64].copy_from_slice(&self.x1.y);
+        out[64..96].copy_from_slice(&self.x2.x);
+        out[96..128].copy_from_slice(&self.x2.y);
+        out
+    }
 }
 
 pub struct DepositWidthdrawProofInputs {
@@ -66,22 +85,465 @@ pub struct DepositWidthdrawProofInputs {
     pub current_balance: Ciphertext,
     pub new_balance: Ciphertext,
     pub amount: U256,
-    pub user_address: Address,
+    /// Address on the plain ERC-20 leg: the payer of a deposit or the recipient of a withdrawal
+    pub counterparty: Address,
     pub token: Address,
+    pub domain: [u8; 32],
+    pub balance_version: U256,
+    /// Token auditor key, all zeros when the token has none
+    pub auditor_pubkey: [u8; 64],
+    /// Amount encrypted under the auditor key, all zeros when the token has none
+    pub auditor_amount: Ciphertext,
+}
+
+/// How the plain leg of a deposit or withdrawal moves tokens
+pub enum PlainLeg {
+    /// ERC-20 `transferFrom` into custody, `transfer` out of it
+    Erc20,
+    /// Native ETH wrapped into or unwrapped from WETH
+    Native,
+    /// EIP-2612 `permit` ahead of `transferFrom`
+    Permit { deadline: U256, v: u8, r: FixedBytes<32>, s: FixedBytes<32> },
+    /// Permit2 signature transfer
+    Permit2 { nonce: U256, deadline: U256, signature: AbiBytes },
 }
 
 pub struct TransferConfidentialProofInputs {
     pub receiver_address: Address,
     pub receiver_pubkey: [u8; 64],
-    pub receiver_current_balance: Ciphertext,
-    pub receiver_new_balance: Ciphertext,
     pub sender_pubkey: [u8; 64],
     pub sender_current_balance: Ciphertext,
     pub sender_new_balance: Ciphertext,
+    /// Transfer amount encrypted under the receiver key, added to their pending balance
+    pub receiver_amount: Ciphertext,
+    pub token: Address,
+    pub domain: [u8; 32],
+    pub sender_balance_version: U256,
+    /// Token auditor key, all zeros when the token has none
+    pub auditor_pubkey: [u8; 64],
+    /// Transfer amount encrypted under the auditor key, all zeros when the token has none
+    pub auditor_amount: Ciphertext,
+}
+
+/// One slot of a batch transfer
+pub struct BatchTransferReceiver {
+    pub receiver_address: Address,
+    pub receiver_pubkey: [u8; 64],
+    /// Amount encrypted under the receiver key, added to their pending balance
+    pub receiver_amount: Ciphertext,
+}
+
+/// Public inputs of a batch transfer: one sender debit for the sum of all receiver amounts.
+pub struct BatchTransferConfidentialProofInputs {
+    /// Filled slots only; slots with a zero receiver address are dropped when decoding
+    pub receivers: Vec<BatchTransferReceiver>,
+    pub sender_pubkey: [u8; 64],
+    pub sender_current_balance: Ciphertext,
+    pub sender_new_balance: Ciphertext,
+    pub token: Address,
+    pub domain: [u8; 32],
+    pub sender_balance_version: U256,
+}
+
+/// Public inputs of the approve proof: escrows `owner_allowance` out of the owner's
+/// balance (returning any previous allowance) and encrypts it for the spender too.
+pub struct ApproveConfidentialProofInputs {
+    pub owner_pubkey: [u8; 64],
+    pub owner_current_balance: Ciphertext,
+    pub spender_address: Address,
+    pub spender_pubkey: [u8; 64],
+    pub token: Address,
+    pub domain: [u8; 32],
+    pub owner_balance_version: U256,
+    pub allowance_version: U256,
+    /// Previous allowance under the owner key, folded back into the balance
+    pub owner_current_allowance: Ciphertext,
+    pub owner_new_balance: Ciphertext,
+    pub owner_allowance: Ciphertext,
+    pub spender_allowance: Ciphertext,
+    /// Proven by the circuit: the new allowance is not 0
+    pub allowance_open: bool,
+}
+
+/// Public inputs of the spender's transfer-from proof: decrements both sides of
+/// the allowance and encrypts the amount for the receiver.
+pub struct TransferFromConfidentialProofInputs {
+    pub owner_address: Address,
+    pub owner_pubkey: [u8; 64],
+    pub receiver_address: Address,
+    pub receiver_pubkey: [u8; 64],
+    pub spender_pubkey: [u8; 64],
+    pub owner_current_allowance: Ciphertext,
+    pub spender_current_allowance: Ciphertext,
     pub token: Address,
+    pub domain: [u8; 32],
+    pub allowance_version: U256,
+    pub owner_new_allowance: Ciphertext,
+    pub spender_new_allowance: Ciphertext,
+    pub receiver_amount: Ciphertext,
+    /// Proven by the circuit: some of the allowance is left
+    pub allowance_open: bool,
+}
+
+/// Public inputs of the re-key proof used by `rotate_user_pk` and `rekey_pending`. Each slot names the token it re-encrypts; unused slots carry the
+/// zero address.
+pub struct RekeyProofInputs {
+    pub old_pubkey: [u8; 64],
+    pub new_pubkey: [u8; 64],
+    pub user_address: Address,
+    pub domain: [u8; 32],
+    pub tokens: [Address; REKEY_SLOTS],
+    /// Balance version for balances, key epoch of the bucket for pending transfers
+    pub versions: [U256; REKEY_SLOTS],
+    /// Ciphertexts under the old key
+    pub old_balances: [Ciphertext; REKEY_SLOTS],
+    /// Same values re-encrypted under the new key
+    pub new_balances: [Ciphertext; REKEY_SLOTS],
+}
+
+/// Number of token slots in a re-key proof. A rotation must re-key every balance at
+/// once, so it is refused for users holding balances in more tokens.
+pub const REKEY_SLOTS: usize = 16;
+
+/// Byte length of the deposit/withdraw public inputs
+pub const DEPOSIT_WITHDRAW_INPUTS_LEN: usize = 672;
+/// Byte length of the confidential transfer public inputs
+pub const TRANSFER_INPUTS_LEN: usize = 832;
+/// Number of receiver slots in a batch transfer proof
+pub const MAX_BATCH_RECEIVERS: usize = 64;
+/// Byte length of the batch transfer public inputs
+pub const BATCH_TRANSFER_INPUTS_LEN: usize = 416 + MAX_BATCH_RECEIVERS * (32 + 64 + 128);
+/// Byte length of the approve public inputs
+pub const APPROVE_INPUTS_LEN: usize = 960;
+/// Byte length of the transfer-from public inputs
+pub const TRANSFER_FROM_INPUTS_LEN: usize = 1024;
+/// Byte length of the re-key public inputs
+pub const REKEY_INPUTS_LEN: usize = 192 + REKEY_SLOTS * (32 + 32 + 128 + 128);
+
+/// Distinct failure modes of a call to a Noir verifier contract.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyProofError {
+    /// The verifier address has no code deployed.
+    NotAContract,
+    /// The static call to `verify` reverted (revert data attached).
+    CallReverted(Vec<u8>),
+    /// The verifier returned data that is not a single 32-byte word.
+    BadReturnLength(usize),
+    /// The verifier returned a 32-byte word that is not a canonical ABI bool.
+    NonCanonicalBool([u8; 32]),
+    /// The verifier ran successfully but rejected the proof.
+    ReturnedFalse,
+}
+
+impl From<VerifyProofError> for ConfidentialERC20Error {
+    fn from(err: VerifyProofError) -> Self {
+        match err {
+            VerifyProofError::NotAContract => Error::VerifierNotContract(VerifierNotContract {}),
+            VerifyProofError::CallReverted(data) => {
+                Error::VerifierCallReverted(VerifierCallReverted { data: data.into() })
+            }
+            VerifyProofError::BadReturnLength(length) => {
+                Error::VerifierMalformedReturn(VerifierMalformedReturn { length: U256::from(length) })
+            }
+            VerifyProofError::NonCanonicalBool(word) => {
+                Error::VerifierNonCanonicalReturn(VerifierNonCanonicalReturn { word: word.into() })
+            }
+            VerifyProofError::ReturnedFalse => Error::ProofRejected(ProofRejected {}),
+        }
+    }
+}
+
+// Errors
+sol! {
+    #![sol(all_derives)]
+
+    // Setup and access control
+    error AlreadyInitialized();
+    error NotOwner(address account);
+    error NotPendingOwner(address account);
+    error MissingRole(bytes32 role, address account);
+    error ReentrantCall();
+
+    // Pause
+    error EnforcedPause();
+    error OperationPaused(uint8 operation);
+    error TokenPaused(address token);
+    error InvalidOperation(uint8 operation);
+
+    // Verifier registry
+    error InvalidCircuit(uint8 circuit);
+    error InvalidVerifier(address verifier);
+    error InvalidVerifierVersion(uint8 circuit, uint32 version);
+    error UnknownVerifierVersion(uint8 circuit, uint32 version);
+    error VerifierVersionDeprecated(uint8 circuit, uint32 version, uint256 cutoff_block);
+    error VerifierVersionInUse(uint8 circuit, uint32 version);
+
+    // Timelocked governance
+    error NotGuardian(address account);
+    error InvalidTimelockDelay(uint256 delay);
+    error ChangeNotPending(uint256 id);
+    error ChangeNotReady(uint256 id, uint256 eta);
+
+    // Compliance
+    error FrozenAccount(address account);
+    error PolicyDenied(address from, address to, address token);
+    error PolicyCallReverted(address policy, bytes data);
+
+    // Users and keys
+    error UserAlreadyRegistered(address user);
+    error UserNotRegistered(address user);
+    error InvalidPublicKey();
+    error UserPkMismatch(address user);
+    error NewPkMismatch(address user);
+    error ReceiverNotRegistered(address receiver);
+    error ReceiverPkMismatch(address receiver);
+
+    // Tokens
+    error TokenNotSupported(address token);
+    error TokenNotConfigured(address token);
+    error InvalidScaleFactor(address token, uint256 scale_factor);
+    error ScaleFactorLocked(address token);
+    error Erc20CallReverted(address token, bytes data);
+    error Erc20TransferFailed(address token, address to, uint256 amount);
+    error Erc20TransferFromFailed(address token, address from, uint256 amount);
+    error AuditorKeyMismatch(address token);
+    error AuditedToken(address token);
+    error FeeOnTransferToken(address token);
+    error InvalidTransferTolerance(address token, uint256 tolerance);
+    error BalanceDeltaMismatch(address token, uint256 expected, uint256 actual);
+    error NotWrappedNative(address token);
+    error EthValueMismatch(uint256 expected, uint256 value);
+    error EthTransferFailed(address to, uint256 amount);
+    error UnexpectedEther(address sender);
+    error Permit2CallReverted(bytes data);
+
+    // Fees
+    error InvalidFeeBps(uint256 fee_bps);
+    error FeeExceedsAmount(address token, uint256 amount, uint256 fee);
+    error NoFeesAccrued(address token);
+
+    // Custody
+    error CustodyUnmeasurable(address token);
+    error NoExcess(address token);
+    error RebasingToken(address token);
+
+    // Proofs
+    error InvalidProofInputsLength(uint256 expected, uint256 actual);
+    error NullifierAlreadyUsed(bytes32 nullifier);
+    error VerifierNotContract();
+    error VerifierCallReverted(bytes data);
+    error VerifierMalformedReturn(uint256 length);
+    error VerifierNonCanonicalReturn(bytes32 word);
+    error ProofRejected();
+    error DomainMismatch(bytes32 domain);
+    error UserAddressMismatch(address user);
+    error PayerMismatch(address payer);
+    error RecipientMismatch(address recipient);
+    error InvalidRecipient(address recipient);
+    error EmptyBatch();
+    error RekeyTokenMismatch(uint256 slot, address token);
+    error RetiredKeyMismatch(address token, address user, uint256 key_epoch);
+    error RekeyIncomplete(address user, address token);
+    error TooManyRekeyBalances(address user, uint256 count);
+
+    // Balances
+    error StaleBalance(address token, address user);
+    error StaleBalanceVersion(address token, address user, uint256 version);
+    error NoPendingBalance(address token, address user);
+
+    // Relayed intents
+    error IntentExpired(uint256 deadline);
+    error InvalidSignature();
+    error RelayerMismatch(address relayer);
+    error FeeReceiverMismatch(address receiver);
+
+    // Allowances
+    error NoAllowance(address token, address owner, address spender);
+    error StaleAllowance(address token, address owner, address spender);
+    error StaleAllowanceVersion(address token, address owner, address spender, uint256 version);
+    error OpenAllowances(address user, uint256 count);
+    error TokenHasOpenAllowances(address token, uint256 count);
+}
+
+/// Every revert of `ConfidentialERC20`, ABI-encoded as the matching Solidity error.
+#[derive(SolidityError, Debug, PartialEq, Eq)]
+pub enum ConfidentialERC20Error {
+    AlreadyInitialized(AlreadyInitialized),
+    NotOwner(NotOwner),
+    NotPendingOwner(NotPendingOwner),
+    MissingRole(MissingRole),
+    ReentrantCall(ReentrantCall),
+    EnforcedPause(EnforcedPause),
+    OperationPaused(OperationPaused),
+    TokenPaused(TokenPaused),
+    InvalidOperation(InvalidOperation),
+    InvalidCircuit(InvalidCircuit),
+    InvalidVerifier(InvalidVerifier),
+    InvalidVerifierVersion(InvalidVerifierVersion),
+    UnknownVerifierVersion(UnknownVerifierVersion),
+    VerifierVersionDeprecated(VerifierVersionDeprecated),
+    VerifierVersionInUse(VerifierVersionInUse),
+    NotGuardian(NotGuardian),
+    InvalidTimelockDelay(InvalidTimelockDelay),
+    ChangeNotPending(ChangeNotPending),
+    ChangeNotReady(ChangeNotReady),
+    FrozenAccount(FrozenAccount),
+    PolicyDenied(PolicyDenied),
+    PolicyCallReverted(PolicyCallReverted),
+    UserAlreadyRegistered(UserAlreadyRegistered),
+    UserNotRegistered(UserNotRegistered),
+    InvalidPublicKey(InvalidPublicKey),
+    UserPkMismatch(UserPkMismatch),
+    NewPkMismatch(NewPkMismatch),
+    ReceiverNotRegistered(ReceiverNotRegistered),
+    ReceiverPkMismatch(ReceiverPkMismatch),
+    TokenNotSupported(TokenNotSupported),
+    TokenNotConfigured(TokenNotConfigured),
+    InvalidScaleFactor(InvalidScaleFactor),
+    ScaleFactorLocked(ScaleFactorLocked),
+    Erc20CallReverted(Erc20CallReverted),
+    Erc20TransferFailed(Erc20TransferFailed),
+    Erc20TransferFromFailed(Erc20TransferFromFailed),
+    AuditorKeyMismatch(AuditorKeyMismatch),
+    AuditedToken(AuditedToken),
+    FeeOnTransferToken(FeeOnTransferToken),
+    InvalidTransferTolerance(InvalidTransferTolerance),
+    BalanceDeltaMismatch(BalanceDeltaMismatch),
+    NotWrappedNative(NotWrappedNative),
+    EthValueMismatch(EthValueMismatch),
+    EthTransferFailed(EthTransferFailed),
+    UnexpectedEther(UnexpectedEther),
+    Permit2CallReverted(Permit2CallReverted),
+    InvalidFeeBps(InvalidFeeBps),
+    FeeExceedsAmount(FeeExceedsAmount),
+    NoFeesAccrued(NoFeesAccrued),
+    CustodyUnmeasurable(CustodyUnmeasurable),
+    NoExcess(NoExcess),
+    RebasingToken(RebasingToken),
+    InvalidProofInputsLength(InvalidProofInputsLength),
+    NullifierAlreadyUsed(NullifierAlreadyUsed),
+    VerifierNotContract(VerifierNotContract),
+    VerifierCallReverted(VerifierCallReverted),
+    VerifierMalformedReturn(VerifierMalformedReturn),
+    VerifierNonCanonicalReturn(VerifierNonCanonicalReturn),
+    ProofRejected(ProofRejected),
+    DomainMismatch(DomainMismatch),
+    UserAddressMismatch(UserAddressMismatch),
+    PayerMismatch(PayerMismatch),
+    RecipientMismatch(RecipientMismatch),
+    InvalidRecipient(InvalidRecipient),
+    EmptyBatch(EmptyBatch),
+    RekeyTokenMismatch(RekeyTokenMismatch),
+    RetiredKeyMismatch(RetiredKeyMismatch),
+    RekeyIncomplete(RekeyIncomplete),
+    TooManyRekeyBalances(TooManyRekeyBalances),
+    StaleBalance(StaleBalance),
+    StaleBalanceVersion(StaleBalanceVersion),
+    NoPendingBalance(NoPendingBalance),
+    IntentExpired(IntentExpired),
+    InvalidSignature(InvalidSignature),
+    RelayerMismatch(RelayerMismatch),
+    FeeReceiverMismatch(FeeReceiverMismatch),
+    NoAllowance(NoAllowance),
+    StaleAllowance(StaleAllowance),
+    StaleAllowanceVersion(StaleAllowanceVersion),
+    OpenAllowances(OpenAllowances),
+    TokenHasOpenAllowances(TokenHasOpenAllowances),
 }
 
+type Error = ConfidentialERC20Error;
+
+/// Fixed-size proof inputs, or `InvalidProofInputsLength`.
+fn fixed_proof_inputs<const N: usize>(proof_inputs: Vec<u8>) -> Result<[u8; N], Error> {
+    let actual = proof_inputs.len();
+    proof_inputs.try_into().map_err(|_| {
+        Error::InvalidProofInputsLength(InvalidProofInputsLength {
+            expected: U256::from(N),
+            actual: U256::from(actual),
+        })
+    })
+}
+
+/// keccak256("VERIFIER_ADMIN_ROLE"): may replace proof verifiers
+pub const VERIFIER_ADMIN_ROLE: FixedBytes<32> =
+    b256!("b194a0b06484f8a501e0bef8877baf2a303f803540f5ddeb9d985c0cd76f3e70");
+/// keccak256("TOKEN_ADMIN_ROLE"): may configure and allowlist tokens
+pub const TOKEN_ADMIN_ROLE: FixedBytes<32> =
+    b256!("9e262e26e9d5bf97da5c389e15529a31bb2b13d89967a4f6eab01792567d5fd6");
+/// keccak256("PAUSER_ROLE"): may pause and unpause operations
+pub const PAUSER_ROLE: FixedBytes<32> =
+    b256!("65d7a28e3265b37a6474929f336521b332c1681b933f6cb9f3376673440d862a");
+/// keccak256("COMPLIANCE_ROLE"): may freeze accounts and set the policy contract
+pub const COMPLIANCE_ROLE: FixedBytes<32> =
+    b256!("442a94f1a1fac79af32856af2a64f63648cfa2ef3b98610a5bb7cbec4cee6985");
+
+/// Pause scopes, as carried by `Paused`/`Unpaused` events. `OP_ALL` is the global
+/// switch (or every operation of a token when the event names one).
+pub const OP_ALL: u8 = 0;
+pub const OP_DEPOSIT: u8 = 1;
+pub const OP_WITHDRAW: u8 = 2;
+pub const OP_TRANSFER: u8 = 3;
+
+/// Circuit ids of the verifier registry, one per proof kind
+pub const CIRCUIT_DEPOSIT: u8 = 1;
+pub const CIRCUIT_WITHDRAW: u8 = 2;
+pub const CIRCUIT_TRANSFER: u8 = 3;
+pub const CIRCUIT_BATCH_TRANSFER: u8 = 4;
+pub const CIRCUIT_APPROVE: u8 = 5;
+pub const CIRCUIT_TRANSFER_FROM: u8 = 6;
+pub const CIRCUIT_REKEY: u8 = 7;
+
+/// Timelocked change kinds, as carried by `ChangeQueued` events
+pub const CHANGE_VERIFIER: u8 = 1;
+pub const CHANGE_TOKEN_REMOVAL: u8 = 2;
+pub const CHANGE_FEE_CONFIG: u8 = 3;
+pub const CHANGE_TIMELOCK_DELAY: u8 = 4;
+pub const CHANGE_GUARDIAN: u8 = 5;
+pub const CHANGE_TOKEN_CONFIG: u8 = 6;
+pub const CHANGE_AUDITOR_KEY: u8 = 7;
+pub const CHANGE_TRANSFER_MODE: u8 = 8;
+
+/// Bounds of the governance timelock delay, in seconds (1 to 30 days); `init` starts at the minimum
+pub const MIN_TIMELOCK_DELAY: u64 = 24 * 60 * 60;
+pub const MAX_TIMELOCK_DELAY: u64 = 30 * 24 * 60 * 60;
+
+/// Upper bound of a token's custody delta tolerance, in underlying units; meant for
+/// the rounding of share-based tokens, not for transfer fees
+pub const MAX_TRANSFER_TOLERANCE: u64 = 100;
+
+/// Upper bound of a token's proportional fee, in basis points (10%)
+pub const MAX_FEE_BPS: u64 = 1_000;
+const BPS_DENOMINATOR: u64 = 10_000;
+
+/// EIP-712 domain of relayed intents
+pub const EIP712_DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+pub const EIP712_NAME: &str = "ConfidentialERC20";
+pub const EIP712_VERSION: &str = "1";
+
+/// Signed by `account` to let a relayer submit `transfer_confidential_by_sig`
+pub const TRANSFER_INTENT_TYPE: &str = "TransferIntent(address account,uint32 circuitVersion,bytes proofInputs,uint32 feeCircuitVersion,bytes feeProofInputs,address relayer,uint256 nonce,uint256 deadline)";
+/// Signed by `account` to let a relayer submit `withdraw_by_sig`
+pub const WITHDRAW_INTENT_TYPE: &str = "WithdrawIntent(address account,address recipient,uint32 circuitVersion,bytes proofInputs,uint32 feeCircuitVersion,bytes feeProofInputs,address relayer,uint256 nonce,uint256 deadline)";
+
+/// ecrecover precompile
+const ECRECOVER: Address = Address::with_last_byte(1);
+/// secp256k1 order / 2: larger `s` values are malleable duplicates
+const SECP256K1_HALF_ORDER: U256 = U256::from_limbs([
+    0xdfe92f46681b20a0,
+    0x5d576e7357a4501d,
+    0xffffffffffffffff,
+    0x7fffffffffffffff,
+]);
+
 pub const WETH_TOKEN_ADDRESS: &str = "0x2836ae2ea2c013acd38028fd0c77b92cccfa2ee4";
+pub const WETH_DECIMALS: u8 = 18;
+/// Canonical Permit2 deployment, used by `deposit_with_permit2`
+pub const PERMIT2_ADDRESS: &str = "0x000000000022d473030f116ddee9f6b43ac78ba3";
+
+/// Since ELGAMAL requires amounts not bigger than 40 bits, proof amounts are scaled
+/// by this factor into underlying token units (WETH default)
+pub const DEFAULT_SCALE_FACTOR: u64 = 1_000_000;
 
 /// This point represents 0 balance in the Grumpkin Curve
 /// G_GENERATOR_X = 1
@@ -101,17 +563,25 @@ pub const G_GENERATOR_Y: [u8; 32] = [
 sol_storage! {
     #[entrypoint]
     pub struct ConfidentialERC20 {
-        // Allowlist of supported underlying ERC-20 tokens
-        mapping(address => bool) supported_tokens;
+        // Allowlist and configuration of supported underlying ERC-20 tokens
+        mapping(address => TokenConfig) token_configs;
+        // Every token ever configured, in configuration order
+        address[] token_list;
 
         // Store per user public key
         mapping(address => bytes32) pk_x;
         mapping(address => bytes32) pk_y;
 
-        // Noir verifier contract (must implement verify(bytes,bytes) -> bool)
-        address deposit_verifier;
-        address withdraw_verifier;
-        address transfer_verifier;
+        // Key rotations: number of rotations per user (its current key epoch), and the
+        // keys retired at each earlier epoch
+        mapping(address => uint256) key_epochs;
+        mapping(address => mapping(uint256 => bytes32)) retired_pk_x;
+        mapping(address => mapping(uint256 => bytes32)) retired_pk_y;
+
+        // Verifier registry: circuit (CIRCUIT_*) => version => verifier
+        mapping(uint8 => mapping(uint32 => VerifierVersion)) verifiers;
+        // Highest registered version of each circuit, used when a call passes version 0
+        mapping(uint8 => uint32) current_verifier_versions;
 
         // Encrypted balances: mapping(token => mapping(user => ciphertext))
         mapping(bytes32 => mapping(bytes32 => bytes32)) balances_x1;
@@ -120,19 +590,153 @@ sol_storage! {
         mapping(bytes32 => mapping(bytes32 => bytes32)) balances_x2;
         mapping(bytes32 => mapping(bytes32 => bytes32)) balances_y2;
 
+        // Number of writes to each encrypted balance, bound into proofs to order them
+        mapping(bytes32 => mapping(bytes32 => uint256)) balance_versions;
+        // Key epoch each written balance is encrypted under
+        mapping(bytes32 => mapping(bytes32 => uint256)) balance_epochs;
+
+        // Incoming transfers not yet merged into the spendable balance
+        mapping(bytes32 => mapping(bytes32 => bytes32)) pending_x1;
+        mapping(bytes32 => mapping(bytes32 => bytes32)) pending_y1;
+
+        mapping(bytes32 => mapping(bytes32 => bytes32)) pending_x2;
+        mapping(bytes32 => mapping(bytes32 => bytes32)) pending_y2;
+        // Key epoch the pending ciphertext is encrypted under
+        mapping(bytes32 => mapping(bytes32 => uint256)) pending_epochs;
+        // Pending transfers received under a retired key:
+        // keccak256(token || user || key epoch) => ciphertext
+        mapping(bytes32 => StalePending) stale_pending;
+
+        // Confidential allowances: keccak256(token || owner || spender) => escrowed allowance
+        mapping(bytes32 => Allowance) allowances;
+        // Allowances each account has granted that are still open
+        mapping(address => uint256) open_allowances;
+        // Open allowances per token, which must be zero to set an auditor key
+        mapping(address => uint256) token_open_allowances;
+
         // Nullifiers for replay protection: hash(proof) -> used?
         mapping(bytes32 => bool) nullifiers;
 
+        // Next EIP-712 intent nonce per account
+        mapping(address => uint256) nonces;
+
         // Reentrancy guard
         ReentrancyGuard guard;
 
+        // Set once by `init`; the owner can't serve as the guard since it may be renounced
+        bool initialized;
+
         // Admin / owner
         address owner;
+        // Proposed owner, set by `transfer_ownership` until it calls `accept_ownership`
+        address pending_owner;
+
+        // Role-based access control: role => account => granted?
+        mapping(bytes32 => mapping(address => bool)) roles;
+
+        // Timelocked governance: verifier, token removal and fee changes wait
+        // `timelock_delay` seconds in `changes` and can be cancelled by the guardian
+        uint256 timelock_delay;
+        address guardian;
+        mapping(uint256 => PendingChange) changes;
+        uint256 change_count;
+
+        // Emergency stop: everything, or a single operation (OP_* => paused?)
+        bool paused_all;
+        mapping(uint8 => bool) paused_operations;
+
+        // Compliance: accounts blocked from every operation
+        mapping(address => bool) frozen;
+        // Optional policy contract consulted as isAllowed(from, to, token) (zero = none)
+        address policy;
+
+        // Protocol fees, charged on the plain ERC-20 leg of deposits and withdrawals
+        address fee_recipient;
+        // Fees collected per token and not yet claimed, in underlying token units
+        mapping(address => uint256) accrued_fees;
+
+        // Custody accounting, in underlying token units: amounts credited to and debited
+        // from encrypted balances. Together with `accrued_fees` they make up the liability.
+        mapping(address => uint256) total_deposited;
+        mapping(address => uint256) total_withdrawn;
     }
 
     pub struct ReentrancyGuard {
         bool locked;
     }
+
+    pub struct PendingChange {
+        // One of CHANGE_*
+        uint8 kind;
+        // Verifier, token or guardian the change applies to
+        address target;
+        // Kind-specific arguments (circuit and version, or fee_bps, flat_fee and fee_cap)
+        uint256 arg0;
+        uint256 arg1;
+        uint256 arg2;
+        // Earliest block timestamp at which the change can be executed
+        uint256 eta;
+        // Queued and neither executed nor cancelled yet
+        bool queued;
+    }
+
+    pub struct VerifierVersion {
+        // Noir verifier contract (must implement verify(bytes,bytes32[]) -> bool)
+        address verifier;
+        // Last block at which proofs of this version are accepted (0 = no cut-off)
+        uint256 cutoff_block;
+    }
+
+    pub struct StalePending {
+        bytes32 x1;
+        bytes32 y1;
+        bytes32 x2;
+        bytes32 y2;
+    }
+
+    pub struct Allowance {
+        // Remaining allowance under the owner's key
+        bytes32 owner_x1;
+        bytes32 owner_y1;
+        bytes32 owner_x2;
+        bytes32 owner_y2;
+        // Same value under the spender's key
+        bytes32 spender_x1;
+        bytes32 spender_y1;
+        bytes32 spender_x2;
+        bytes32 spender_y2;
+        // Number of writes, bound into proofs like balance versions
+        uint256 version;
+        // The last proof showed a non-zero allowance
+        bool open;
+    }
+
+    pub struct TokenConfig {
+        // Allowlisted: deposits, withdrawals and transfers are accepted
+        bool enabled;
+        // Underlying token units per proof amount unit
+        uint256 scale_factor;
+        // Decimals of the underlying ERC-20
+        uint8 decimals;
+        // Emergency stop for every operation on this token
+        bool paused;
+        // Proportional fee in basis points of the underlying amount
+        uint256 fee_bps;
+        // Fixed fee per deposit/withdraw, in underlying units
+        uint256 flat_fee;
+        // Maximum fee per operation, in underlying units (0 = uncapped)
+        uint256 fee_cap;
+        // Auditor Grumpkin key: every amount is also encrypted under it (zero = no auditor)
+        bytes32 auditor_x;
+        bytes32 auditor_y;
+        // The token charges a fee on transfers: deposits are refused, withdrawals stay open
+        bool fee_on_transfer;
+        // Balances change without transfers (share-based or rebasing token)
+        bool rebasing;
+        // Accepted gap between the proven amount and the measured custody delta, in underlying
+        // units; only rebasing tokens may have one
+        uint256 transfer_tolerance;
+    }
 }
 
 // Helpers
@@ -148,37 +752,166 @@ fn balance_key(token: Address, user: Address) -> (FixedBytes<32>, FixedBytes<32>
     (address_to_bytes32(token), address_to_bytes32(user))
 }
 
+fn allowance_key(token: Address, owner: Address, spender: Address) -> FixedBytes<32> {
+    keccak256([token.as_slice(), owner.as_slice(), spender.as_slice()].concat())
+}
+
+fn stale_pending_key(token: Address, user: Address, key_epoch: U256) -> FixedBytes<32> {
+    keccak256([token.as_slice(), user.as_slice(), &key_epoch.to_be_bytes::<32>()].concat())
+}
+
 // Events
 sol! {
-    /// Encrypted transfer occurred (logs new encrypted balances)
+    /// Encrypted transfer occurred (logs new encrypted balances).
+    /// Ciphertexts are 128 bytes: x1.x, x1.y, x2.x, x2.y.
     event TransferConfidential(
         address indexed token,
         address indexed from,
         address indexed to,
+        uint256 sender_balance_version,
+        bytes sender_new_balance,
+        bytes receiver_amount,
+        bytes receiver_new_pending,
+        bytes auditor_amount,
     );
 
-    /// Plain deposit with encrypted balance update
+    /// Plain deposit with encrypted balance update. `amount` is in underlying token units.
     event Deposit(
         address indexed token,
         address indexed user_address,
+        address indexed payer,
+        uint256 amount,
+        uint256 balance_version,
+        bytes new_balance,
+        bytes auditor_amount,
     );
 
-    /// Plain withdrawal with encrypted balance update
+    /// Plain withdrawal with encrypted balance update. `amount` is in underlying token units.
     event Withdraw(
         address indexed token,
-        address indexed user_address
+        address indexed user_address,
+        address indexed recipient,
+        uint256 amount,
+        uint256 balance_version,
+        bytes new_balance,
+        bytes auditor_amount,
+    );
+
+    /// Confidential allowance set; the owner's balance now excludes the escrowed allowance
+    event ApprovalConfidential(
+        address indexed token,
+        address indexed owner,
+        address indexed spender,
+        uint256 owner_balance_version,
+        uint256 allowance_version,
+        bytes owner_new_balance,
+        bytes owner_allowance,
+        bytes spender_allowance,
+    );
+
+    /// Spender gave up an allowance; the escrowed remainder went to the owner's pending balance
+    event AllowanceDropped(
+        address indexed token,
+        address indexed owner,
+        address indexed spender,
+        uint256 allowance_version,
+        bytes owner_new_pending,
+    );
+
+    /// Spender moved escrowed funds to `to`'s pending balance
+    event TransferFromConfidential(
+        address indexed token,
+        address indexed owner,
+        address indexed spender,
+        address to,
+        uint256 allowance_version,
+        bytes owner_allowance,
+        bytes spender_allowance,
+        bytes receiver_amount,
+        bytes receiver_new_pending,
+    );
+
+    /// Balance re-encrypted under a rotated key, one per token
+    event BalanceReencrypted(
+        address indexed token,
+        address indexed user,
+        uint256 balance_version,
+        bytes new_balance,
+    );
+
+    /// Pending transfers received under a retired key, re-encrypted and merged into
+    /// the pending balance under the current key
+    event PendingReencrypted(
+        address indexed token,
+        address indexed user,
+        uint256 key_epoch,
+        bytes new_pending,
     );
 
-    event VerifierUpdated(address deposit_verifier, address withdraw_verifier, address transfer_verifier);
     event TokenAllowlistUpdated(address indexed token, bool allowed);
+    event TokenConfigUpdated(address indexed token, uint256 scale_factor, uint8 decimals);
+    event TokenTransferModeUpdated(address indexed token, bool fee_on_transfer, bool rebasing, uint256 transfer_tolerance);
+    event AuditorKeyUpdated(address indexed token, bytes auditor_pk);
+    event FeeConfigUpdated(address indexed token, uint256 fee_bps, uint256 flat_fee, uint256 fee_cap);
+    event FeeRecipientUpdated(address indexed previous_recipient, address indexed new_recipient);
+    event FeesAccrued(address indexed token, address indexed account, uint256 fee, uint256 total_accrued);
+    event FeesClaimed(address indexed token, address indexed recipient, uint256 amount);
+    event ExcessSwept(address indexed token, address indexed to, uint256 amount);
+    event ChangeQueued(uint256 indexed id, uint8 indexed kind, address target, uint256 arg0, uint256 arg1, uint256 arg2, uint256 eta);
+    event ChangeExecuted(uint256 indexed id, uint8 indexed kind);
+    event ChangeCancelled(uint256 indexed id, uint8 indexed kind, address indexed account);
+    event TimelockDelayUpdated(uint256 previous_delay, uint256 new_delay);
+    event GuardianUpdated(address previous_guardian, address new_guardian);
+    event VerifierRegistered(uint8 indexed circuit, uint32 indexed version, address verifier);
+    event VerifierDeprecated(uint8 indexed circuit, uint32 indexed version, uint256 cutoff_block);
     event UserPkRegistered(address indexed user, bytes pk);
+    event UserPkRotated(address indexed user, uint256 key_epoch, bytes old_pk, bytes new_pk);
+    event NullifierSpent(bytes32 indexed nullifier);
+    event IntentRelayed(address indexed account, address indexed relayer, uint256 nonce);
+    event OwnershipTransferStarted(address indexed previous_owner, address indexed new_owner);
+    event OwnershipTransferred(address indexed previous_owner, address indexed new_owner);
+    event Paused(address indexed account, uint8 operation, address token);
+    event Unpaused(address indexed account, uint8 operation, address token);
+    event RoleGranted(bytes32 indexed role, address indexed account, address indexed sender);
+    event RoleRevoked(bytes32 indexed role, address indexed account, address indexed sender);
+    event AccountFrozen(address indexed account, address indexed sender);
+    event AccountUnfrozen(address indexed account, address indexed sender);
+    event PolicyUpdated(address previous_policy, address new_policy);
+    event PendingApplied(address indexed token, address indexed user, uint256 balance_version, bytes new_balance);
 
     // Standard ERC-20
     function transfer(address to, uint256 amount) external returns (bool);
     function transferFrom(address from, address to, uint256 amount) external returns (bool);
+    function balanceOf(address account) external view returns (uint256);
+
+    // WETH
+    function deposit() external payable;
+    function withdraw(uint256 amount) external;
+
+    // EIP-2612
+    function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external;
+
+    // Permit2 signature transfers
+    struct TokenPermissions {
+        address token;
+        uint256 amount;
+    }
+    struct PermitTransferFrom {
+        TokenPermissions permitted;
+        uint256 nonce;
+        uint256 deadline;
+    }
+    struct SignatureTransferDetails {
+        address to;
+        uint256 requestedAmount;
+    }
+    function permitTransferFrom(PermitTransferFrom permit, SignatureTransferDetails transferDetails, address owner, bytes signature) external;
 
     // Noir verifier
     function verify(bytes proof, bytes32[] publicInputs) external view returns (bool);
+
+    // Compliance policy
+    function isAllowed(address from, address to, address token) external view returns (bool);
 }
 
 #[public]
@@ -189,27 +922,45 @@ impl ConfidentialERC20 {
         deposit_verifier: Address, 
         withdraw_verifier: Address, 
         transfer_verifier: Address
-    ) -> Result<(), Vec<u8>> {
-        if self.owner.get() != Address::ZERO {
-            return Err("Already initialized".into());
+    ) -> Result<(), Error> {
+        if self.initialized.get() {
+            return Err(Error::AlreadyInitialized(AlreadyInitialized {}));
+        }
+        self.initialized.set(true);
+
+        let sender = self.vm().msg_sender();
+        self.owner.set(sender);
+        log(self.vm(), OwnershipTransferred {
+            previous_owner: Address::ZERO,
+            new_owner: sender,
+        });
+
+        // The deployer starts with every role and hands them out afterwards
+        for role in [VERIFIER_ADMIN_ROLE, TOKEN_ADMIN_ROLE, PAUSER_ROLE, COMPLIANCE_ROLE] {
+            self._grant_role(role, sender);
         }
 
-        self.deposit_verifier.set(deposit_verifier);
-        self.withdraw_verifier.set(withdraw_verifier);
-        self.transfer_verifier.set(transfer_verifier);
-        self.owner.set(self.vm().msg_sender());
+        self.timelock_delay.set(U256::from(MIN_TIMELOCK_DELAY));
+        log(self.vm(), TimelockDelayUpdated { previous_delay: U256::ZERO, new_delay: U256::from(MIN_TIMELOCK_DELAY) });
 
-        // For now we only support WETH token
-        self.supported_tokens.setter(Address::from_str(WETH_TOKEN_ADDRESS).unwrap()).set(true);
+        // Version 1 of the base circuits; the others are registered when deployed
+        self._register_verifier(CIRCUIT_DEPOSIT, 1, deposit_verifier)?;
+        self._register_verifier(CIRCUIT_WITHDRAW, 1, withdraw_verifier)?;
+        self._register_verifier(CIRCUIT_TRANSFER, 1, transfer_verifier)?;
+
+        // WETH is supported out of the box, other tokens are onboarded by the owner
+        let weth = Address::from_str(WETH_TOKEN_ADDRESS).unwrap();
+        self._set_token_config(weth, U256::from(DEFAULT_SCALE_FACTOR), WETH_DECIMALS)?;
+        self._set_supported_token(weth, true);
         Ok(())
     }
 
     // Expects public key should be two points of the elliptic curve (we should use same elliptic
     // curve as the prover in this case Noir Grumpkin curve to generate this public key)
-    pub fn register_user_pk(&mut self, public_key: [u8; 64]) -> Result<(), Vec<u8>> {
+    pub fn register_user_pk(&mut self, public_key: [u8; 64]) -> Result<(), Error> {
         let sender = self.vm().msg_sender();
         if self._get_user_pk(sender) != [0u8; 64] {
-            return Err("User already registered".into());
+            return Err(Error::UserAlreadyRegistered(UserAlreadyRegistered { user: sender }));
         }
 
         // Safely convert to FixedBytes<32>
@@ -219,162 +970,1302 @@ impl ConfidentialERC20 {
         self.pk_x.setter(sender).set(pk_x);
         self.pk_y.setter(sender).set(pk_y);
 
-        evm::log(UserPkRegistered {
+        // No balance is written here: every token reads as an encryption of 0 until
+        // its first update (see `_get_balance`)
+        log(self.vm(), UserPkRegistered {
             user: sender,
             pk: public_key.into(),
         });
 
-        // Set the initial balance to 0
-        let initial_balance = Ciphertext {
-            x1: Point { x: G_GENERATOR_X, y: G_GENERATOR_Y },
-            x2: Point { x: *pk_x, y: *pk_y },
+        Ok(())
+    }
+
+    /// Replace the caller's public key, re-encrypting the balance of every token the
+    /// caller holds one in under it.
+    ///
+    /// Each rotation starts a new key epoch. The proof must cover every token with a
+    /// written balance, so no funds are left under the retired key; callers with
+    /// balances in more than `REKEY_SLOTS` tokens can't rotate. Only balances are
+    /// re-keyed: transfers already pending under the retired key are kept apart (see
+    /// `rekey_pending`), so an incoming transfer can't invalidate the proof.
+    pub fn rotate_user_pk(
+        &mut self,
+        new_pk: [u8; 64],
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs: [u8; REKEY_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+
+        self._when_not_paused(OP_ALL)?;
+        let verifier = self._verifier(CIRCUIT_REKEY, circuit_version)?;
+        self._non_reentrant()?;
+
+        let sender = self.vm().msg_sender();
+        let old_pk = self._get_user_pk(sender);
+        if old_pk == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: sender }));
+        }
+        if new_pk == [0u8; 64] || new_pk == old_pk {
+            self._release_reentrancy();
+            return Err(Error::InvalidPublicKey(InvalidPublicKey {}));
+        }
+        // Granted allowances are escrowed under the owner's key and are not re-keyed;
+        // allowances held as spender don't block a rotation and can be dropped
+        let open_allowances = self.open_allowances.get(sender);
+        if !open_allowances.is_zero() {
+            self._release_reentrancy();
+            return Err(Error::OpenAllowances(OpenAllowances { user: sender, count: open_allowances }));
+        }
+
+        let rekey_proof_inputs = match self._verify_rekey_proof(verifier, proof_inputs, proof, sender, new_pk) {
+            Ok(rekey_proof_inputs) => rekey_proof_inputs,
+            Err(e) => {
+                self._release_reentrancy();
+                return Err(e);
+            }
         };
+        if rekey_proof_inputs.old_pubkey != old_pk {
+            self._release_reentrancy();
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: sender }));
+        }
+        if let Err(e) = self._sanity_checks_for_rekey(sender, &rekey_proof_inputs, false) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+        if let Err(e) = self._check_rekey_covers_balances(sender, &rekey_proof_inputs) {
+            self._release_reentrancy();
+            return Err(e);
+        }
 
-        self._set_balance(
-            Address::from_str(WETH_TOKEN_ADDRESS).unwrap(), sender, &initial_balance
-        );
+        // Retire the old key under its epoch before moving to the next one
+        let key_epoch = self.key_epochs.get(sender);
+        self.retired_pk_x.setter(sender).setter(key_epoch).set(self.pk_x.get(sender));
+        self.retired_pk_y.setter(sender).setter(key_epoch).set(self.pk_y.get(sender));
+        self.key_epochs.setter(sender).set(key_epoch + U256::from(1));
+
+        let pk_x = FixedBytes::<32>::try_from(&new_pk[..32]).unwrap();
+        let pk_y = FixedBytes::<32>::try_from(&new_pk[32..]).unwrap();
+        self.pk_x.setter(sender).set(pk_x);
+        self.pk_y.setter(sender).set(pk_y);
+
+        self._rekey_balances(sender, &rekey_proof_inputs);
+
+        log(self.vm(), UserPkRotated {
+            user: sender,
+            key_epoch: key_epoch + U256::from(1),
+            old_pk: old_pk.into(),
+            new_pk: new_pk.into(),
+        });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Re-encrypt transfers that were pending under one of the caller's retired keys,
+    /// and merge them into the pending balance under the current key. Slot versions
+    /// carry the key epoch of each bucket.
+    pub fn rekey_pending(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs: [u8; REKEY_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+
+        self._when_not_paused(OP_ALL)?;
+        let verifier = self._verifier(CIRCUIT_REKEY, circuit_version)?;
+        self._non_reentrant()?;
+
+        let sender = self.vm().msg_sender();
+        let user_pk = self._get_user_pk(sender);
+        if user_pk == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: sender }));
+        }
+        let rekey_proof_inputs = match self._verify_rekey_proof(verifier, proof_inputs, proof, sender, user_pk) {
+            Ok(rekey_proof_inputs) => rekey_proof_inputs,
+            Err(e) => {
+                self._release_reentrancy();
+                return Err(e);
+            }
+        };
+        if let Err(e) = self._sanity_checks_for_rekey(sender, &rekey_proof_inputs, true) {
+            self._release_reentrancy();
+            return Err(e);
+        }
 
+        for i in 0..REKEY_SLOTS {
+            let token = rekey_proof_inputs.tokens[i];
+            if token == Address::ZERO {
+                continue;
+            }
+            let key_epoch = rekey_proof_inputs.versions[i];
+            self._clear_pending_at(token, sender, key_epoch);
+            let new_pending = self._add_pending(token, sender, &rekey_proof_inputs.new_balances[i]);
+            log(self.vm(), PendingReencrypted {
+                token,
+                user: sender,
+                key_epoch,
+                new_pending: new_pending.to_bytes().into(),
+            });
+        }
+
+        self._release_reentrancy();
         Ok(())
     }
 
     /// Get encrypted balance for (token, user). Only pk owner can decrypt this balance.
+    /// Balances never written before read as the canonical encryption of 0, (G, pk).
     pub fn balance_of_enc(&self, token: Address, user: Address) -> [u8; 128] {
+        self._get_balance(token, user).to_bytes()
+    }
+
+    /// Get the encrypted sum of incoming transfers not yet applied to the balance.
+    /// All-zero bytes mean nothing is pending.
+    pub fn pending_balance_of_enc(&self, token: Address, user: Address) -> [u8; 128] {
+        self._pending_at(token, user, self.key_epochs.get(user)).to_bytes()
+    }
+
+    /// Get the encrypted sum of transfers received under the key `user` held at
+    /// `key_epoch`. All-zero bytes mean nothing is pending.
+    pub fn pending_balance_at_epoch(&self, token: Address, user: Address, key_epoch: U256) -> [u8; 128] {
+        self._pending_at(token, user, key_epoch).to_bytes()
+    }
+
+    /// Number of times the encrypted balance for (token, user) has been written.
+    /// Proofs must target the current version.
+    pub fn balance_version(&self, token: Address, user: Address) -> U256 {
         let (t, u) = balance_key(token, user);
-        let bx1: [u8; 32] = self.balances_x1.get(t).get(u).into();
-        let bx2: [u8; 32] = self.balances_y1.get(t).get(u).into();
+        self.balance_versions.get(t).get(u)
+    }
 
-        let by1: [u8; 32] = self.balances_x2.get(t).get(u).into();
-        let by2: [u8; 32] = self.balances_y2.get(t).get(u).into();
+    /// Number of key rotations of `user`, i.e. the epoch of its current key.
+    pub fn key_epoch(&self, user: Address) -> U256 {
+        self.key_epochs.get(user)
+    }
+
+    /// Key epoch the balance of (token, user) is encrypted under. Rotations re-key
+    /// every balance, so it follows the user's current epoch.
+    pub fn balance_key_epoch(&self, token: Address, user: Address) -> U256 {
+        self._balance_epoch(token, user)
+    }
 
-        let mut result = [0u8; 128];
-        result[0..32].copy_from_slice(&bx1);
-        result[32..64].copy_from_slice(&bx2);
-        result[64..96].copy_from_slice(&by1);
-        result[96..128].copy_from_slice(&by2);
-        result
+    /// Public key `user` held at `key_epoch`; all-zero past the current epoch.
+    pub fn get_user_pk_at(&self, user: Address, key_epoch: U256) -> [u8; 64] {
+        self._user_pk_at(user, key_epoch)
     }
 
     /// Deposit/Withdraw plain ERC-20 tokens.
     ///
+    /// The encrypted balance updated is always the one of the account whose key
+    /// produced the proof. `counterparty` is the address on the plain ERC-20 leg:
+    /// the payer for deposits, the recipient for withdrawals.
+    ///
+    /// Like every proof entry point, `circuit_version` picks the registered verifier
+    /// the proof was built for; 0 means the circuit's current version.
+    ///
     /// Required public inputs:
     /// user_pubkey: pub EmbeddedCurvePoint,
     /// current_balance_x1: pub EmbeddedCurvePoint,
     /// current_balance_x2: pub EmbeddedCurvePoint,
-    /// user_address: pub Field,
+    /// counterparty: pub Field,
     /// token: pub Field
     /// amount: pub Field,
+    /// domain: pub Field,
+    /// balance_version: pub Field,
     /// new_balance_x1: pub EmbeddedCurvePoint,
     /// new_balance_x2: pub EmbeddedCurvePoint,
     pub fn deposit(
         &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, true, PlainLeg::Erc20, sender, sender)
+    }
+
+    /// Deposit paid by the caller into `beneficiary`'s encrypted balance.
+    /// The proof is produced by the beneficiary and binds the caller as payer.
+    pub fn deposit_for(
+        &mut self,
+        beneficiary: Address,
+        circuit_version: u32,
         proof_inputs: Vec<u8>,
         proof: AbiBytes,
-    ) -> Result<(), Vec<u8>> {
-        let proof_inputs_fixed: [u8; 416] = proof_inputs.try_into()?;
-        self._deposit_widthdraw(proof_inputs_fixed, proof, true)
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let payer = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, true, PlainLeg::Erc20, beneficiary, payer)
     }
 
     pub fn withdraw(
         &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, false, PlainLeg::Erc20, sender, sender)
+    }
+
+    /// Withdraw from the caller's encrypted balance to a recipient bound in the proof.
+    pub fn withdraw_to(
+        &mut self,
+        recipient: Address,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        if recipient == Address::ZERO {
+            return Err(Error::InvalidRecipient(InvalidRecipient { recipient }));
+        }
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, false, PlainLeg::Erc20, sender, recipient)
+    }
+
+    /// Deposit native ETH: `msg.value` is wrapped into WETH and must equal the proof
+    /// amount times the scale factor, plus the deposit fee. The proof token must be WETH.
+    #[payable]
+    pub fn deposit_eth(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, true, PlainLeg::Native, sender, sender)
+    }
+
+    /// `deposit` without a prior `approve`: the caller's EIP-2612 permit for the proof
+    /// amount plus the deposit fee is submitted to the token before `transferFrom`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn deposit_with_permit(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+        deadline: U256,
+        v: u8,
+        r: FixedBytes<32>,
+        s: FixedBytes<32>,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        let leg = PlainLeg::Permit { deadline, v, r, s };
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, true, leg, sender, sender)
+    }
+
+    /// `deposit` for tokens without native permit: the caller signs a Permit2
+    /// `PermitTransferFrom` for the token and exactly the proof amount plus the
+    /// deposit fee, with this contract as spender.
+    pub fn deposit_with_permit2(
+        &mut self,
+        circuit_version: u32,
         proof_inputs: Vec<u8>,
         proof: AbiBytes,
-    ) -> Result<(), Vec<u8>> {
-        let proof_inputs_fixed: [u8; 416] = proof_inputs.try_into()?;
-        self._deposit_widthdraw(proof_inputs_fixed, proof, false)
+        nonce: U256,
+        deadline: U256,
+        signature: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        let leg = PlainLeg::Permit2 { nonce, deadline, signature };
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, true, leg, sender, sender)
+    }
+
+    /// Withdraw WETH from the caller's encrypted balance, unwrapped and paid out as native ETH.
+    pub fn withdraw_eth(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        let sender = self.vm().msg_sender();
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, false, PlainLeg::Native, sender, sender)
+    }
+
+    /// Plain ETH is only accepted from WETH, when `withdraw_eth` unwraps.
+    /// The SDK fixes the receive hook to raw revert bytes; `_accept_ether` keeps the typed error.
+    #[receive]
+    pub fn receive(&mut self) -> Result<(), Vec<u8>> {
+        Ok(self._accept_ether()?)
     }
 
     /// Confidential balance-to-balance transfer.
     ///
+    /// The receiver's balance is not part of the proof: the amount encrypted under
+    /// their key is added to their pending balance, so incoming transfers never
+    /// invalidate the receiver's own in-flight proofs.
+    ///
     /// Required public inputs:
     /// receiver_address: pub Field,
     /// receiver_pubkey: pub EmbeddedCurvePoint,
-    /// receiver_current_balance_x1: pub EmbeddedCurvePoint,
-    /// receiver_current_balance_x2: pub EmbeddedCurvePoint,
     /// sender_pubkey: pub EmbeddedCurvePoint,
     /// sender_current_balance_x1: pub EmbeddedCurvePoint,
     /// sender_current_balance_x2: pub EmbeddedCurvePoint,
     /// token: pub Field
+    /// domain: pub Field,
+    /// sender_balance_version: pub Field,
     /// sender_new_balance_x1: pub EmbeddedCurvePoint,
     /// sender_new_balance_x2: pub EmbeddedCurvePoint,
-    /// receiver_new_balance_x1: pub EmbeddedCurvePoint,
-    /// receiver_new_balance_x2: pub EmbeddedCurvePoint,
+    /// receiver_amount_x1: pub EmbeddedCurvePoint,
+    /// receiver_amount_x2: pub EmbeddedCurvePoint,
     pub fn transfer_confidential(
         &mut self,
+        circuit_version: u32,
         proof_inputs: Vec<u8>,
         proof: AbiBytes,
-    ) -> Result<(), Vec<u8>> {
-        self._non_reentrant()?;
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; TRANSFER_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+
+        self._when_not_paused(OP_TRANSFER)?;
+        let from = self.vm().msg_sender();
+        self._transfer_confidential(from, circuit_version, proof_inputs_fixed, proof)
+    }
+
+    /// Pay up to `MAX_BATCH_RECEIVERS` receivers with a single proof.
+    ///
+    /// The proof debits the sender once, by the sum of all amounts, and encrypts each
+    /// amount under its receiver's key. Every receiver's pending balance is credited in
+    /// the same call, so the batch lands entirely or not at all. Unused slots carry the
+    /// zero address and are ignored. One `TransferConfidential` event is emitted per
+    /// receiver, each with the sender's final balance.
+    ///
+    /// Required public inputs:
+    /// MAX_BATCH_RECEIVERS x (receiver_address, receiver_pubkey), sender_pubkey,
+    /// sender_current_balance, token, domain, sender_balance_version, then outputs
+    /// sender_new_balance and MAX_BATCH_RECEIVERS x receiver_amount.
+    pub fn transfer_confidential_batch(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; BATCH_TRANSFER_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
 
-        let proof_inputs_fixed: [u8; 704] = proof_inputs.try_into()?;
+        self._when_not_paused(OP_TRANSFER)?;
+        let verifier = self._verifier(CIRCUIT_BATCH_TRANSFER, circuit_version)?;
+        self._non_reentrant()?;
 
         let from = self.vm().msg_sender();
-        let sender_pubkey = self._get_user_pk(from);
-        if sender_pubkey == [0u8; 64] {
+        if self._get_user_pk(from) == [0u8; 64] {
             self._release_reentrancy();
-            return Err("User not registered".into());
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: from }));
         }
 
-        if let Err(e) = self._verify_proof(&proof_inputs_fixed, proof, self.transfer_verifier.get()) {
+        if let Err(e) = self._spend_nullifier(verifier, &proof, &proof_inputs_fixed) {
             self._release_reentrancy();
-            return Err("Proof verification failed".into());
+            return Err(e);
         }
 
-        let transfer_proof_inputs = self._decode_transfer_confidential_proof_inputs(proof_inputs_fixed);
-
-        let result = self._sanity_checks_for_transfer(from, &transfer_proof_inputs);
-        if let Err(err) = result {
+        if let Err(e) = self._verify_proof(&proof_inputs_fixed, proof, verifier) {
             self._release_reentrancy();
-            return Err(err);
+            return Err(e.into());
         }
 
-        let token = transfer_proof_inputs.token;
-        let receiver_address = transfer_proof_inputs.receiver_address;
-        let sender_new_balance = transfer_proof_inputs.sender_new_balance;
-        let receiver_new_balance = transfer_proof_inputs.receiver_new_balance;
+        let batch_proof_inputs = self._decode_batch_transfer_confidential_proof_inputs(proof_inputs_fixed);
+        if let Err(e) = self._sanity_checks_for_batch_transfer(from, &batch_proof_inputs) {
+            self._release_reentrancy();
+            return Err(e);
+        }
 
-        self._set_balance(token, from, &sender_new_balance);
-        self._set_balance(token, receiver_address, &receiver_new_balance);
+        let token = batch_proof_inputs.token;
+        let sender_new_balance = batch_proof_inputs.sender_new_balance;
+        let sender_balance_version = self._set_balance(token, from, &sender_new_balance);
 
-        // Emit event with new ciphertexts for indexing/off-chain
-        evm::log(TransferConfidential {
-            token,
-            from,
-            to: transfer_proof_inputs.receiver_address
-        });
+        for receiver in batch_proof_inputs.receivers.iter() {
+            let receiver_new_pending = self._add_pending(token, receiver.receiver_address, &receiver.receiver_amount);
+            log(self.vm(), TransferConfidential {
+                token,
+                from,
+                to: receiver.receiver_address,
+                sender_balance_version,
+                sender_new_balance: sender_new_balance.to_bytes().into(),
+                receiver_amount: receiver.receiver_amount.to_bytes().into(),
+                receiver_new_pending: receiver_new_pending.to_bytes().into(),
+                // Batches are refused for audited tokens
+                auditor_amount: [0u8; 128].into(),
+            });
+        }
 
         self._release_reentrancy();
         Ok(())
     }
 
-    // --- Admin ---
-    pub fn set_verifier(
+    /// `transfer_confidential` on behalf of `account`, submitted by a relayer.
+    ///
+    /// `account` authorizes the call with an EIP-712 `TransferIntent` signature over
+    /// both proofs' public inputs, its current nonce and a deadline, so it never needs
+    /// gas of its own. The relayer can be paid confidentially: `fee_proof_inputs` is an
+    /// ordinary transfer proof from `account` to the relayer, built on the balance the
+    /// main transfer leaves behind. Pass empty fee inputs for an unpaid relay.
+    /// Both circuit versions are signed, so the relayer cannot pick another verifier.
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer_confidential_by_sig(
         &mut self,
-        deposit_verifier: Address,
-        withdraw_verifier: Address,
-        transfer_verifier: Address
-    ) -> Result<(), Vec<u8>> {
-        self._only_owner()?;
-        self.deposit_verifier.set(deposit_verifier);
-        self.withdraw_verifier.set(withdraw_verifier);
-        self.transfer_verifier.set(transfer_verifier);
-        evm::log(VerifierUpdated {
-            deposit_verifier,
-            withdraw_verifier,
-            transfer_verifier,
-        });
-        Ok(())
-    }
+        account: Address,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+        fee_circuit_version: u32,
+        fee_proof_inputs: Vec<u8>,
+        fee_proof: AbiBytes,
+        relayer: Address,
+        deadline: U256,
+        signature: AbiBytes,
+    ) -> Result<(), Error> {
+        self._when_not_paused(OP_TRANSFER)?;
+
+        let struct_hash = keccak256(
+            [
+                keccak256(TRANSFER_INTENT_TYPE).as_slice(),
+                address_to_bytes32(account).as_slice(),
+                &U256::from(circuit_version).to_be_bytes::<32>(),
+                keccak256(&proof_inputs).as_slice(),
+                &U256::from(fee_circuit_version).to_be_bytes::<32>(),
+                keccak256(&fee_proof_inputs).as_slice(),
+                address_to_bytes32(relayer).as_slice(),
+                &self.nonces.get(account).to_be_bytes::<32>(),
+                &deadline.to_be_bytes::<32>(),
+            ]
+            .concat(),
+        );
+        self._use_intent(account, struct_hash, relayer, deadline, &signature)?;
 
-    pub fn get_deposit_verifier(&self) -> Address {
-        self.deposit_verifier.get()
+        let proof_inputs_fixed: [u8; TRANSFER_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        self._transfer_confidential(account, circuit_version, proof_inputs_fixed, proof)?;
+        self._pay_relayer_fee(account, fee_circuit_version, fee_proof_inputs, fee_proof)
     }
 
-    pub fn get_withdraw_verifier(&self) -> Address {
-        self.withdraw_verifier.get()
-    }
+    /// `withdraw_to` on behalf of `account`, submitted by a relayer.
+    ///
+    /// Authorized by an EIP-712 `WithdrawIntent` signature, with the same optional
+    /// confidential relayer fee as `transfer_confidential_by_sig`. A paid relay also
+    /// passes the transfer pause gate; unpaid relayed exits keep working in withdraw-only mode.
+    #[allow(clippy::too_many_arguments)]
+    pub fn withdraw_by_sig(
+        &mut self,
+        account: Address,
+        recipient: Address,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+        fee_circuit_version: u32,
+        fee_proof_inputs: Vec<u8>,
+        fee_proof: AbiBytes,
+        relayer: Address,
+        deadline: U256,
+        signature: AbiBytes,
+    ) -> Result<(), Error> {
+        if recipient == Address::ZERO {
+            return Err(Error::InvalidRecipient(InvalidRecipient { recipient }));
+        }
 
-    pub fn get_transfer_verifier(&self) -> Address {
-        self.transfer_verifier.get()
+        let struct_hash = keccak256(
+            [
+                keccak256(WITHDRAW_INTENT_TYPE).as_slice(),
+                address_to_bytes32(account).as_slice(),
+                address_to_bytes32(recipient).as_slice(),
+                &U256::from(circuit_version).to_be_bytes::<32>(),
+                keccak256(&proof_inputs).as_slice(),
+                &U256::from(fee_circuit_version).to_be_bytes::<32>(),
+                keccak256(&fee_proof_inputs).as_slice(),
+                address_to_bytes32(relayer).as_slice(),
+                &self.nonces.get(account).to_be_bytes::<32>(),
+                &deadline.to_be_bytes::<32>(),
+            ]
+            .concat(),
+        );
+        self._use_intent(account, struct_hash, relayer, deadline, &signature)?;
+
+        let proof_inputs_fixed: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+        self._deposit_widthdraw(circuit_version, proof_inputs_fixed, proof, false, PlainLeg::Erc20, account, recipient)?;
+        self._pay_relayer_fee(account, fee_circuit_version, fee_proof_inputs, fee_proof)
+    }
+
+    /// Next intent nonce of `account`.
+    pub fn nonces(&self, account: Address) -> U256 {
+        self.nonces.get(account)
+    }
+
+    /// EIP-712 domain separator of relayed intents.
+    pub fn eip712_domain_separator(&self) -> FixedBytes<32> {
+        keccak256(
+            [
+                keccak256(EIP712_DOMAIN_TYPE).as_slice(),
+                keccak256(EIP712_NAME).as_slice(),
+                keccak256(EIP712_VERSION).as_slice(),
+                &U256::from(self.vm().chain_id()).to_be_bytes::<32>(),
+                address_to_bytes32(self.vm().contract_address()).as_slice(),
+            ]
+            .concat(),
+        )
+    }
+
+    /// Set the caller's confidential allowance for a spender.
+    ///
+    /// The allowance is escrowed: the proof moves it out of the owner's balance
+    /// (returning any previous allowance first) and encrypts it under both the owner's
+    /// and the spender's keys. Approving 0 revokes and refunds the allowance.
+    ///
+    /// Required public inputs:
+    /// owner_pubkey, owner_current_balance, spender_address, spender_pubkey, token,
+    /// domain, owner_balance_version, allowance_version, owner_current_allowance,
+    /// then outputs owner_new_balance, owner_allowance, spender_allowance,
+    /// allowance_open.
+    pub fn approve_confidential(
+        &mut self,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; APPROVE_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+
+        // Only moves funds between the owner's own buckets, so it stays open in
+        // withdraw-only mode and escrowed funds can always be reclaimed
+        self._when_not_paused(OP_ALL)?;
+        let verifier = self._verifier(CIRCUIT_APPROVE, circuit_version)?;
+        self._non_reentrant()?;
+
+        let owner = self.vm().msg_sender();
+        if self._get_user_pk(owner) == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: owner }));
+        }
+
+        if let Err(e) = self._spend_nullifier(verifier, &proof, &proof_inputs_fixed) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+
+        if let Err(e) = self._verify_proof(&proof_inputs_fixed, proof, verifier) {
+            self._release_reentrancy();
+            return Err(e.into());
+        }
+
+        let approve_proof_inputs = self._decode_approve_confidential_proof_inputs(proof_inputs_fixed);
+        if let Err(e) = self._sanity_checks_for_approve(owner, &approve_proof_inputs) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+
+        let token = approve_proof_inputs.token;
+        let spender = approve_proof_inputs.spender_address;
+        let owner_balance_version = self._set_balance(token, owner, &approve_proof_inputs.owner_new_balance);
+        let allowance_version = self._set_allowance(
+            token,
+            owner,
+            spender,
+            &approve_proof_inputs.owner_allowance,
+            &approve_proof_inputs.spender_allowance,
+            approve_proof_inputs.allowance_open,
+        );
+
+        log(self.vm(), ApprovalConfidential {
+            token,
+            owner,
+            spender,
+            owner_balance_version,
+            allowance_version,
+            owner_new_balance: approve_proof_inputs.owner_new_balance.to_bytes().into(),
+            owner_allowance: approve_proof_inputs.owner_allowance.to_bytes().into(),
+            spender_allowance: approve_proof_inputs.spender_allowance.to_bytes().into(),
+        });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Move escrowed funds from `owner`'s allowance to a receiver, as the spender.
+    ///
+    /// The spender's proof decrements the allowance under both keys and encrypts the
+    /// amount under the receiver's key; the amount lands in the receiver's pending
+    /// balance exactly like `transfer_confidential`. The owner's balance is untouched.
+    ///
+    /// Required public inputs:
+    /// owner_address, owner_pubkey, receiver_address, receiver_pubkey, spender_pubkey,
+    /// owner_current_allowance, spender_current_allowance, token, domain,
+    /// allowance_version, then outputs owner_new_allowance, spender_new_allowance,
+    /// receiver_amount, allowance_open.
+    pub fn transfer_from_confidential(
+        &mut self,
+        owner: Address,
+        circuit_version: u32,
+        proof_inputs: Vec<u8>,
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let proof_inputs_fixed: [u8; TRANSFER_FROM_INPUTS_LEN] = fixed_proof_inputs(proof_inputs)?;
+
+        self._when_not_paused(OP_TRANSFER)?;
+        let verifier = self._verifier(CIRCUIT_TRANSFER_FROM, circuit_version)?;
+        self._non_reentrant()?;
+
+        let spender = self.vm().msg_sender();
+        if self._get_user_pk(spender) == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: spender }));
+        }
+
+        if let Err(e) = self._spend_nullifier(verifier, &proof, &proof_inputs_fixed) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+
+        if let Err(e) = self._verify_proof(&proof_inputs_fixed, proof, verifier) {
+            self._release_reentrancy();
+            return Err(e.into());
+        }
+
+        let transfer_from_proof_inputs = self._decode_transfer_from_confidential_proof_inputs(proof_inputs_fixed);
+        if let Err(e) = self._sanity_checks_for_transfer_from(owner, spender, &transfer_from_proof_inputs) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+
+        let token = transfer_from_proof_inputs.token;
+        let receiver_address = transfer_from_proof_inputs.receiver_address;
+        let allowance_version = self._set_allowance(
+            token,
+            owner,
+            spender,
+            &transfer_from_proof_inputs.owner_new_allowance,
+            &transfer_from_proof_inputs.spender_new_allowance,
+            transfer_from_proof_inputs.allowance_open,
+        );
+        let receiver_new_pending =
+            self._add_pending(token, receiver_address, &transfer_from_proof_inputs.receiver_amount);
+
+        log(self.vm(), TransferFromConfidential {
+            token,
+            owner,
+            spender,
+            to: receiver_address,
+            allowance_version,
+            owner_allowance: transfer_from_proof_inputs.owner_new_allowance.to_bytes().into(),
+            spender_allowance: transfer_from_proof_inputs.spender_new_allowance.to_bytes().into(),
+            receiver_amount: transfer_from_proof_inputs.receiver_amount.to_bytes().into(),
+            receiver_new_pending: receiver_new_pending.to_bytes().into(),
+        });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Get the encrypted allowance of (token, owner, spender) as
+    /// (under the owner's key, under the spender's key). Never-approved allowances
+    /// read as encryptions of 0.
+    pub fn allowance_of_enc(&self, token: Address, owner: Address, spender: Address) -> ([u8; 128], [u8; 128]) {
+        let (owner_allowance, spender_allowance) = self._get_allowance(token, owner, spender);
+        (owner_allowance.to_bytes(), spender_allowance.to_bytes())
+    }
+
+    /// Number of times the allowance of (token, owner, spender) has been written.
+    pub fn allowance_version(&self, token: Address, owner: Address, spender: Address) -> U256 {
+        self.allowances.get(allowance_key(token, owner, spender)).version.get()
+    }
+
+    /// Number of allowances `user` has granted that are not revoked, dropped or spent.
+    /// Keys can only be rotated while it is zero.
+    pub fn open_allowance_count(&self, user: Address) -> U256 {
+        self.open_allowances.get(user)
+    }
+
+    /// Number of open allowances on `token`. An auditor key can only be set on the
+    /// token while it is zero.
+    pub fn token_open_allowance_count(&self, token: Address) -> U256 {
+        self.token_open_allowances.get(token)
+    }
+
+    /// Give up an allowance granted to the caller by `owner`.
+    ///
+    /// The escrowed remainder under the owner's key is returned to the owner's pending
+    /// balance and both sides are reset to encryptions of 0, so a spender can shed an
+    /// allowance they never asked for or can no longer use, e.g. after rotating
+    /// their key.
+    pub fn drop_allowance(&mut self, token: Address, owner: Address) -> Result<(), Error> {
+        // Only returns escrowed funds to their owner, so it stays open in withdraw-only mode
+        self._when_not_paused(OP_ALL)?;
+        self._when_token_not_paused(token)?;
+        self._non_reentrant()?;
+
+        let spender = self.vm().msg_sender();
+        if !self._allowance_is_open(token, owner, spender) {
+            self._release_reentrancy();
+            return Err(Error::NoAllowance(NoAllowance { token, owner, spender }));
+        }
+
+        let (owner_allowance, _) = self._get_allowance(token, owner, spender);
+        let allowance_version = self._set_allowance(
+            token,
+            owner,
+            spender,
+            &self._zero_balance(owner),
+            &self._zero_balance(spender),
+            false,
+        );
+        let owner_new_pending = self._add_pending(token, owner, &owner_allowance);
+
+        log(self.vm(), AllowanceDropped {
+            token,
+            owner,
+            spender,
+            allowance_version,
+            owner_new_pending: owner_new_pending.to_bytes().into(),
+        });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Merge the caller's pending incoming transfers into their spendable balance.
+    ///
+    /// The merge is a public homomorphic addition of two ciphertexts under the
+    /// caller's own key, so it is computed on-chain and takes no proof: a proof would
+    /// only attest a sum anyone can recompute. Transfers pending under a retired key are
+    /// merged with `rekey_pending` instead.
+    pub fn apply_pending(&mut self, token: Address) -> Result<(), Error> {
+        // Stays available in withdraw-only mode, so received funds can still exit
+        self._when_not_paused(OP_ALL)?;
+        self._when_token_not_paused(token)?;
+        self._non_reentrant()?;
+
+        let user = self.vm().msg_sender();
+        if self._get_user_pk(user) == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user }));
+        }
+
+        let key_epoch = self._balance_epoch(token, user);
+        let pending = self._pending_at(token, user, key_epoch);
+        if pending == Ciphertext::zero() {
+            self._release_reentrancy();
+            return Err(Error::NoPendingBalance(NoPendingBalance { token, user }));
+        }
+
+        let new_balance = grumpkin::add_ciphertexts(&self._get_balance(token, user), &pending);
+        let balance_version = self._set_balance(token, user, &new_balance);
+        self._clear_pending_at(token, user, key_epoch);
+
+        log(self.vm(), PendingApplied {
+            token,
+            user,
+            balance_version,
+            new_balance: new_balance.to_bytes().into(),
+        });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    // --- Admin ---
+    /// Queue the registration of `verifier` as `version` of `circuit` (one of the
+    /// CIRCUIT_* ids); it lands after the timelock delay.
+    ///
+    /// Registered versions are never overwritten, so proofs built for an older version
+    /// keep verifying during a migration until it is deprecated. The highest registered
+    /// version becomes the circuit's current version.
+    pub fn register_verifier(&mut self, circuit: u8, version: u32, verifier: Address) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        if version == 0 {
+            return Err(Error::InvalidVerifierVersion(InvalidVerifierVersion { circuit, version }));
+        }
+        self._queue_verifier(circuit, version, verifier)
+    }
+
+    /// Stop accepting proofs of `version` of `circuit` after `cutoff_block`; 0 lifts
+    /// the cut-off. The current version can't be deprecated.
+    pub fn deprecate_verifier(&mut self, circuit: u8, version: u32, cutoff_block: U256) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        if self.get_verifier(circuit, version).0 == Address::ZERO {
+            return Err(Error::UnknownVerifierVersion(UnknownVerifierVersion { circuit, version }));
+        }
+        if version == self.current_verifier_version(circuit) {
+            return Err(Error::VerifierVersionInUse(VerifierVersionInUse { circuit, version }));
+        }
+        self.verifiers.setter(U8::from(circuit)).setter(U32::from(version)).cutoff_block.set(cutoff_block);
+        log(self.vm(), VerifierDeprecated { circuit, version, cutoff_block });
+        Ok(())
+    }
+
+    /// Queue the deposit, withdraw and transfer verifiers as the next version of their
+    /// circuits. Earlier versions stay valid until deprecated.
+    pub fn set_verifier(
+        &mut self,
+        deposit_verifier: Address,
+        withdraw_verifier: Address,
+        transfer_verifier: Address
+    ) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        self._queue_verifier(CIRCUIT_DEPOSIT, 0, deposit_verifier)?;
+        self._queue_verifier(CIRCUIT_WITHDRAW, 0, withdraw_verifier)?;
+        self._queue_verifier(CIRCUIT_TRANSFER, 0, transfer_verifier)
+    }
+
+    /// Execute a queued change once its delay has passed. Requires the authority that
+    /// queued it: `VERIFIER_ADMIN_ROLE`, `TOKEN_ADMIN_ROLE`, or the owner for the rest.
+    pub fn execute_change(&mut self, id: U256) -> Result<(), Error> {
+        let change = self.changes.get(id);
+        let (queued, kind, eta) = (change.queued.get(), change.kind.get().to::<u8>(), change.eta.get());
+        if !queued {
+            return Err(Error::ChangeNotPending(ChangeNotPending { id }));
+        }
+        self._only_change_admin(kind)?;
+        if U256::from(self.vm().block_timestamp()) < eta {
+            return Err(Error::ChangeNotReady(ChangeNotReady { id, eta }));
+        }
+        self._execute_change(id)
+    }
+
+    /// Drop a queued change. Callable by the guardian or the owner.
+    pub fn cancel_change(&mut self, id: U256) -> Result<(), Error> {
+        let account = self.vm().msg_sender();
+        if account != self.guardian.get() && account != self.owner.get() {
+            return Err(Error::NotGuardian(NotGuardian { account }));
+        }
+        let change = self.changes.get(id);
+        let (queued, kind) = (change.queued.get(), change.kind.get().to::<u8>());
+        if !queued {
+            return Err(Error::ChangeNotPending(ChangeNotPending { id }));
+        }
+        self.changes.setter(id).queued.set(false);
+        log(self.vm(), ChangeCancelled { id, kind, account });
+        Ok(())
+    }
+
+    /// Queue a new timelock delay, between `MIN_TIMELOCK_DELAY` and `MAX_TIMELOCK_DELAY`
+    /// seconds. The current delay applies to the change.
+    pub fn set_timelock_delay(&mut self, delay: U256) -> Result<(), Error> {
+        self._only_owner()?;
+        if delay < U256::from(MIN_TIMELOCK_DELAY) || delay > U256::from(MAX_TIMELOCK_DELAY) {
+            return Err(Error::InvalidTimelockDelay(InvalidTimelockDelay { delay }));
+        }
+        self._queue_change(CHANGE_TIMELOCK_DELAY, Address::ZERO, delay, U256::ZERO, U256::ZERO)
+    }
+
+    /// Queue a new guardian; the zero address leaves cancelling to the owner.
+    pub fn set_guardian(&mut self, guardian: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        self._queue_change(CHANGE_GUARDIAN, guardian, U256::ZERO, U256::ZERO, U256::ZERO)
+    }
+
+    /// Queue the deposit/withdraw fee of a configured token: `fee_bps` of the underlying
+    /// amount plus `flat_fee`, capped at `fee_cap` when it is nonzero.
+    pub fn set_fee_config(
+        &mut self,
+        token: Address,
+        fee_bps: U256,
+        flat_fee: U256,
+        fee_cap: U256,
+    ) -> Result<(), Error> {
+        self._only_owner()?;
+        if self.token_configs.get(token).scale_factor.get().is_zero() {
+            return Err(Error::TokenNotConfigured(TokenNotConfigured { token }));
+        }
+        if fee_bps > U256::from(MAX_FEE_BPS) {
+            return Err(Error::InvalidFeeBps(InvalidFeeBps { fee_bps }));
+        }
+        self._queue_change(CHANGE_FEE_CONFIG, token, fee_bps, flat_fee, fee_cap)
+    }
+
+    /// Queue the auditor key of a configured token. Once set, deposit, withdraw and
+    /// transfer proofs must also encrypt the amount under it; an all-zero key removes
+    /// the auditor. The auditor can decrypt amounts but never spend.
+    ///
+    /// The approve, transfer-from and batch circuits carry no auditor ciphertext, so
+    /// an audited token takes no batch transfers, no `transfer_from_confidential` and
+    /// no approvals other than revokes. A key can't be set while the token has open
+    /// allowances, which would otherwise be stranded; this is checked again when the
+    /// change executes.
+    pub fn set_auditor_key(&mut self, token: Address, auditor_pk: [u8; 64]) -> Result<(), Error> {
+        self._only_owner()?;
+        if self.token_configs.get(token).scale_factor.get().is_zero() {
+            return Err(Error::TokenNotConfigured(TokenNotConfigured { token }));
+        }
+        if auditor_pk != [0u8; 64] {
+            self._check_no_token_allowances(token)?;
+        }
+        let (x, y) = (U256::from_be_slice(&auditor_pk[..32]), U256::from_be_slice(&auditor_pk[32..]));
+        self._queue_change(CHANGE_AUDITOR_KEY, token, x, y, U256::ZERO)
+    }
+
+    /// Set the account `claim_fees` pays out to.
+    pub fn set_fee_recipient(&mut self, fee_recipient: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        if fee_recipient == Address::ZERO {
+            return Err(Error::InvalidRecipient(InvalidRecipient { recipient: fee_recipient }));
+        }
+        let previous_recipient = self.fee_recipient.get();
+        self.fee_recipient.set(fee_recipient);
+        log(self.vm(), FeeRecipientUpdated { previous_recipient, new_recipient: fee_recipient });
+        Ok(())
+    }
+
+    /// Send every accrued fee of `token` to the fee recipient. Callable by the owner
+    /// or the fee recipient.
+    pub fn claim_fees(&mut self, token: Address) -> Result<(), Error> {
+        let recipient = self.fee_recipient.get();
+        if self.vm().msg_sender() != recipient {
+            self._only_owner()?;
+        }
+        if recipient == Address::ZERO {
+            return Err(Error::InvalidRecipient(InvalidRecipient { recipient }));
+        }
+        let amount = self.accrued_fees.get(token);
+        if amount.is_zero() {
+            return Err(Error::NoFeesAccrued(NoFeesAccrued { token }));
+        }
+        self._non_reentrant()?;
+
+        self.accrued_fees.setter(token).set(U256::ZERO);
+        if let Err(e) = self._transfer(token, recipient, amount) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+        log(self.vm(), FeesClaimed { token, recipient, amount });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Send the tokens held above the tracked liability (for example tokens sent to the
+    /// contract by mistake) to `to`. Rebasing tokens can't be swept: their excess is the
+    /// holders' yield. Only callable by the owner.
+    pub fn sweep_excess(&mut self, token: Address, to: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        if to == Address::ZERO {
+            return Err(Error::InvalidRecipient(InvalidRecipient { recipient: to }));
+        }
+        if self.token_configs.get(token).rebasing.get() {
+            return Err(Error::RebasingToken(RebasingToken { token }));
+        }
+        let balance = self._custody_balance(token)?
+            .ok_or(Error::CustodyUnmeasurable(CustodyUnmeasurable { token }))?;
+        let amount = balance.saturating_sub(self._custody_liability(token));
+        if amount.is_zero() {
+            return Err(Error::NoExcess(NoExcess { token }));
+        }
+        self._non_reentrant()?;
+
+        if let Err(e) = self._transfer(token, to, amount) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+        log(self.vm(), ExcessSwept { token, to, amount });
+
+        self._release_reentrancy();
+        Ok(())
+    }
+
+    /// Set the scale factor and decimals of an underlying token. The first
+    /// configuration applies at once; reconfigurations are queued behind the timelock.
+    pub fn set_token_config(
+        &mut self,
+        token: Address,
+        scale_factor: U256,
+        decimals: u8,
+    ) -> Result<(), Error> {
+        self._only_role(TOKEN_ADMIN_ROLE)?;
+        if scale_factor.is_zero() {
+            return Err(Error::InvalidScaleFactor(InvalidScaleFactor { token, scale_factor }));
+        }
+        let current_scale_factor = self.token_configs.get(token).scale_factor.get();
+        if current_scale_factor.is_zero() {
+            return self._set_token_config(token, scale_factor, decimals);
+        }
+        if current_scale_factor != scale_factor && !self.total_deposited.get(token).is_zero() {
+            return Err(Error::ScaleFactorLocked(ScaleFactorLocked { token }));
+        }
+        self._queue_change(CHANGE_TOKEN_CONFIG, token, scale_factor, U256::from(decimals), U256::ZERO)
+    }
+
+    /// Queue the fee-on-transfer and rebasing flags of a configured token. A rebasing
+    /// token may tolerate a gap of up to `MAX_TRANSFER_TOLERANCE` underlying units between
+    /// a proven amount and the custody balance delta; other tokens need exact deltas.
+    pub fn set_token_transfer_mode(
+        &mut self,
+        token: Address,
+        fee_on_transfer: bool,
+        rebasing: bool,
+        transfer_tolerance: U256,
+    ) -> Result<(), Error> {
+        self._only_role(TOKEN_ADMIN_ROLE)?;
+        if self.token_configs.get(token).scale_factor.get().is_zero() {
+            return Err(Error::TokenNotConfigured(TokenNotConfigured { token }));
+        }
+        if transfer_tolerance > U256::from(MAX_TRANSFER_TOLERANCE) || (!rebasing && !transfer_tolerance.is_zero()) {
+            return Err(Error::InvalidTransferTolerance(InvalidTransferTolerance { token, tolerance: transfer_tolerance }));
+        }
+        let flags = U256::from(fee_on_transfer as u8) | (U256::from(rebasing as u8) << 1);
+        self._queue_change(CHANGE_TRANSFER_MODE, token, flags, transfer_tolerance, U256::ZERO)
+    }
+
+    /// Queue the next versions of the `approve_confidential` and
+    /// `transfer_from_confidential` verifiers.
+    pub fn set_allowance_verifiers(
+        &mut self,
+        approve_verifier: Address,
+        transfer_from_verifier: Address,
+    ) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        self._queue_verifier(CIRCUIT_APPROVE, 0, approve_verifier)?;
+        self._queue_verifier(CIRCUIT_TRANSFER_FROM, 0, transfer_from_verifier)
+    }
+
+    /// Queue the next version of the batch verifier used by `transfer_confidential_batch`.
+    pub fn set_batch_transfer_verifier(&mut self, batch_transfer_verifier: Address) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        self._queue_verifier(CIRCUIT_BATCH_TRANSFER, 0, batch_transfer_verifier)
+    }
+
+    /// Queue the next version of the re-key verifier used by `rotate_user_pk`.
+    pub fn set_rekey_verifier(&mut self, rekey_verifier: Address) -> Result<(), Error> {
+        self._only_role(VERIFIER_ADMIN_ROLE)?;
+        self._queue_verifier(CIRCUIT_REKEY, 0, rekey_verifier)
+    }
+
+    /// Add or remove a token from the allowlist. The token must be configured first.
+    /// Additions apply at once; removals are queued behind the timelock so holders
+    /// can still exit.
+    pub fn set_supported_token(&mut self, token: Address, allowed: bool) -> Result<(), Error> {
+        self._only_role(TOKEN_ADMIN_ROLE)?;
+        if !allowed {
+            return self._queue_change(CHANGE_TOKEN_REMOVAL, token, U256::ZERO, U256::ZERO, U256::ZERO);
+        }
+        if self.token_configs.get(token).scale_factor.get().is_zero() {
+            return Err(Error::TokenNotConfigured(TokenNotConfigured { token }));
+        }
+        self._set_supported_token(token, true);
+        Ok(())
+    }
+
+    /// Start a two-step ownership transfer; `new_owner` must call `accept_ownership`.
+    /// Proposing the zero address cancels a pending transfer.
+    pub fn transfer_ownership(&mut self, new_owner: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        self.pending_owner.set(new_owner);
+        log(self.vm(), OwnershipTransferStarted {
+            previous_owner: self.owner.get(),
+            new_owner,
+        });
+        Ok(())
+    }
+
+    /// Complete an ownership transfer started by `transfer_ownership`.
+    pub fn accept_ownership(&mut self) -> Result<(), Error> {
+        let sender = self.vm().msg_sender();
+        if sender != self.pending_owner.get() || sender == Address::ZERO {
+            return Err(Error::NotPendingOwner(NotPendingOwner { account: sender }));
+        }
+        self._set_owner(sender);
+        Ok(())
+    }
+
+    /// Give up ownership for good. Roles already granted keep working, but can no
+    /// longer be granted or revoked.
+    pub fn renounce_ownership(&mut self) -> Result<(), Error> {
+        self._only_owner()?;
+        self._set_owner(Address::ZERO);
+        Ok(())
+    }
+
+    /// Grant `role` to `account`. The owner administers every role.
+    pub fn grant_role(&mut self, role: FixedBytes<32>, account: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        self._grant_role(role, account);
+        Ok(())
+    }
+
+    /// Revoke `role` from `account`.
+    pub fn revoke_role(&mut self, role: FixedBytes<32>, account: Address) -> Result<(), Error> {
+        self._only_owner()?;
+        self._revoke_role(role, account);
+        Ok(())
+    }
+
+    /// Give up a role held by the caller.
+    pub fn renounce_role(&mut self, role: FixedBytes<32>) -> Result<(), Error> {
+        let sender = self.vm().msg_sender();
+        self._revoke_role(role, sender);
+        Ok(())
+    }
+
+    pub fn has_role(&self, role: FixedBytes<32>, account: Address) -> bool {
+        self.roles.get(role).get(account)
+    }
+
+    /// Pause every operation. Withdrawals included: see `set_withdraw_only` to keep exits open.
+    pub fn pause(&mut self) -> Result<(), Error> {
+        self._only_pauser()?;
+        self._set_paused(OP_ALL, Address::ZERO, true);
+        Ok(())
+    }
+
+    pub fn unpause(&mut self) -> Result<(), Error> {
+        self._only_pauser()?;
+        self._set_paused(OP_ALL, Address::ZERO, false);
+        Ok(())
+    }
+
+    /// Pause or unpause one of OP_DEPOSIT, OP_WITHDRAW or OP_TRANSFER.
+    pub fn set_operation_paused(&mut self, operation: u8, paused: bool) -> Result<(), Error> {
+        self._only_pauser()?;
+        if !(OP_DEPOSIT..=OP_TRANSFER).contains(&operation) {
+            return Err(Error::InvalidOperation(InvalidOperation { operation }));
+        }
+        self._set_paused(operation, Address::ZERO, paused);
+        Ok(())
+    }
+
+    /// Pause or unpause every operation on one token.
+    pub fn set_token_paused(&mut self, token: Address, paused: bool) -> Result<(), Error> {
+        self._only_pauser()?;
+        self._set_paused(OP_ALL, token, paused);
+        Ok(())
+    }
+
+    /// Withdraw-only mode: deposits and transfers stop, withdrawals (and applying
+    /// pending transfers) stay open so users can always exit.
+    pub fn set_withdraw_only(&mut self) -> Result<(), Error> {
+        self._only_pauser()?;
+        self._set_paused(OP_ALL, Address::ZERO, false);
+        self._set_paused(OP_DEPOSIT, Address::ZERO, true);
+        self._set_paused(OP_TRANSFER, Address::ZERO, true);
+        self._set_paused(OP_WITHDRAW, Address::ZERO, false);
+        Ok(())
+    }
+
+    /// Freeze or unfreeze an account. A frozen account can't deposit, withdraw, send,
+    /// receive or approve. Requires the owner or `COMPLIANCE_ROLE`.
+    pub fn set_frozen(&mut self, account: Address, frozen: bool) -> Result<(), Error> {
+        self._only_compliance()?;
+        if self.frozen.get(account) == frozen {
+            return Ok(());
+        }
+        self.frozen.setter(account).set(frozen);
+        let sender = self.vm().msg_sender();
+        if frozen {
+            log(self.vm(), AccountFrozen { account, sender });
+        } else {
+            log(self.vm(), AccountUnfrozen { account, sender });
+        }
+        Ok(())
+    }
+
+    /// Set the policy contract asked `isAllowed(from, to, token)` before every operation;
+    /// the zero address disables it. Requires the owner or `COMPLIANCE_ROLE`.
+    pub fn set_policy(&mut self, policy: Address) -> Result<(), Error> {
+        self._only_compliance()?;
+        let previous_policy = self.policy.get();
+        self.policy.set(policy);
+        log(self.vm(), PolicyUpdated { previous_policy, new_policy: policy });
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, account: Address) -> bool {
+        self.frozen.get(account)
+    }
+
+    pub fn get_policy(&self) -> Address {
+        self.policy.get()
+    }
+
+    /// Whether the global pause is on.
+    pub fn paused(&self) -> bool {
+        self.paused_all.get()
+    }
+
+    /// Whether an operation is currently blocked, globally or on its own.
+    pub fn is_operation_paused(&self, operation: u8) -> bool {
+        self.paused_all.get() || self.paused_operations.get(U8::from(operation))
+    }
+
+    pub fn is_token_paused(&self, token: Address) -> bool {
+        self.token_configs.get(token).paused.get()
+    }
+
+    pub fn get_pending_owner(&self) -> Address {
+        self.pending_owner.get()
+    }
+
+    pub fn get_timelock_delay(&self) -> U256 {
+        self.timelock_delay.get()
+    }
+
+    pub fn get_guardian(&self) -> Address {
+        self.guardian.get()
+    }
+
+    /// Changes queued and neither executed nor cancelled, as
+    /// (id, kind, target, arg0, arg1, arg2, eta), so users can exit before one lands.
+    pub fn pending_changes(&self) -> Vec<(U256, u8, Address, U256, U256, U256, U256)> {
+        let mut pending = Vec::new();
+        let mut id = U256::ZERO;
+        while id < self.change_count.get() {
+            let change = self.changes.get(id);
+            if change.queued.get() {
+                pending.push((
+                    id,
+                    change.kind.get().to(),
+                    change.target.get(),
+                    change.arg0.get(),
+                    change.arg1.get(),
+                    change.arg2.get(),
+                    change.eta.get(),
+                ));
+            }
+            id += U256::from(1);
+        }
+        pending
+    }
+
+    /// Verifier and cut-off block of one version of a circuit; the zero address when
+    /// the version is not registered.
+    pub fn get_verifier(&self, circuit: u8, version: u32) -> (Address, U256) {
+        let circuit_versions = self.verifiers.getter(U8::from(circuit));
+        let entry = circuit_versions.getter(U32::from(version));
+        (entry.verifier.get(), entry.cutoff_block.get())
+    }
+
+    /// Version used when a call passes circuit version 0.
+    pub fn current_verifier_version(&self, circuit: u8) -> u32 {
+        self.current_verifier_versions.get(U8::from(circuit)).to()
+    }
+
+    // Current-version verifier of each circuit
+    pub fn get_deposit_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_DEPOSIT)
+    }
+
+    pub fn get_withdraw_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_WITHDRAW)
+    }
+
+    pub fn get_transfer_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_TRANSFER)
+    }
+
+    pub fn get_batch_transfer_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_BATCH_TRANSFER)
+    }
+
+    pub fn get_approve_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_APPROVE)
+    }
+
+    pub fn get_transfer_from_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_TRANSFER_FROM)
+    }
+
+    pub fn get_rekey_verifier(&self) -> Address {
+        self._current_verifier(CIRCUIT_REKEY)
     }
 
     pub fn get_owner(&self) -> Address {
@@ -382,20 +2273,109 @@ impl ConfidentialERC20 {
     }
 
     pub fn is_supported_token(&self, token: Address) -> bool {
-        self.supported_tokens.get(token)
+        self.token_configs.get(token).enabled.get()
+    }
+
+    /// Returns (enabled, scale_factor, decimals) for a token.
+    pub fn get_token_config(&self, token: Address) -> (bool, U256, u8) {
+        let config = self.token_configs.get(token);
+        (config.enabled.get(), config.scale_factor.get(), config.decimals.get().to())
+    }
+
+    /// Fee-on-transfer flag, rebasing flag and custody delta tolerance of a token.
+    pub fn get_token_transfer_mode(&self, token: Address) -> (bool, bool, U256) {
+        let config = self.token_configs.get(token);
+        (config.fee_on_transfer.get(), config.rebasing.get(), config.transfer_tolerance.get())
+    }
+
+    /// Auditor key of a token, all zeros when it has none.
+    pub fn get_auditor_key(&self, token: Address) -> [u8; 64] {
+        self._get_auditor_key(token)
+    }
+
+    /// Returns (fee_bps, flat_fee, fee_cap) for a token.
+    pub fn get_fee_config(&self, token: Address) -> (U256, U256, U256) {
+        let config = self.token_configs.get(token);
+        (config.fee_bps.get(), config.flat_fee.get(), config.fee_cap.get())
+    }
+
+    pub fn get_fee_recipient(&self) -> Address {
+        self.fee_recipient.get()
+    }
+
+    /// Fees of `token` collected and not yet claimed, in underlying units.
+    pub fn accrued_fees(&self, token: Address) -> U256 {
+        self.accrued_fees.get(token)
+    }
+
+    /// Underlying units credited to encrypted balances by deposits.
+    pub fn total_deposited(&self, token: Address) -> U256 {
+        self.total_deposited.get(token)
+    }
+
+    /// Underlying units debited from encrypted balances by withdrawals, fees included.
+    pub fn total_withdrawn(&self, token: Address) -> U256 {
+        self.total_withdrawn.get(token)
+    }
+
+    /// Solvency check of a token, as (total_deposited, total_withdrawn, accrued_fees,
+    /// liability, balance, solvent): the liability is what the contract owes to
+    /// encrypted balances and the fee recipient, the balance its live `balanceOf`.
+    pub fn custody_report(&self, token: Address) -> Result<(U256, U256, U256, U256, U256, bool), Error> {
+        let balance = self._custody_balance(token)?
+            .ok_or(Error::CustodyUnmeasurable(CustodyUnmeasurable { token }))?;
+        let liability = self._custody_liability(token);
+        Ok((
+            self.total_deposited.get(token),
+            self.total_withdrawn.get(token),
+            self.accrued_fees.get(token),
+            liability,
+            balance,
+            balance >= liability,
+        ))
+    }
+
+    /// Fee charged on a deposit or withdrawal of `amount` underlying units of `token`.
+    /// Depositors must approve `amount + fee`; withdrawals pay out `amount - fee`.
+    pub fn quote_fee(&self, token: Address, amount: U256) -> U256 {
+        let config = self.token_configs.get(token);
+        let fee = amount * config.fee_bps.get() / U256::from(BPS_DENOMINATOR) + config.flat_fee.get();
+        let fee_cap = config.fee_cap.get();
+        if !fee_cap.is_zero() && fee > fee_cap {
+            return fee_cap;
+        }
+        fee
+    }
+
+    /// Every configured token. A rotation's re-key proof must cover each one the user
+    /// holds a balance in.
+    pub fn get_token_list(&self) -> Vec<Address> {
+        (0..self.token_list.len())
+            .filter_map(|i| self.token_list.get(i))
+            .collect()
     }
 
     pub fn get_user_pk(&self, user: Address) -> [u8; 64] {
         self._get_user_pk(user)
     }
+
+    /// Domain separator every proof must carry as its `domain` public input.
+    pub fn domain_separator(&self) -> FixedBytes<32> {
+        FixedBytes::from(self._domain_separator())
+    }
+
+    /// Whether a proof with this nullifier has already been accepted.
+    pub fn is_nullifier_used(&self, nullifier: FixedBytes<32>) -> bool {
+        self.nullifiers.get(nullifier)
+    }
 }
 
 // --- Internal logic ---
 impl ConfidentialERC20 {
     // Reentrancy
-    fn _non_reentrant(&mut self) -> Result<(), Vec<u8>> {
+    fn _non_reentrant(&mut self) -> Result<(), Error> {
         if self.guard.locked.get() {
-            return Err("Reentrant call".into());
+            return Err(Error::ReentrantCall(ReentrantCall {}));
         }
         self.guard.locked.set(true);
         Ok(())
@@ -405,17 +2385,396 @@ impl ConfidentialERC20 {
         self.guard.locked.set(false);
     }
 
+    // Reject plain ETH from anyone but WETH
+    fn _accept_ether(&self) -> Result<(), Error> {
+        let sender = self.vm().msg_sender();
+        if sender != Address::from_str(WETH_TOKEN_ADDRESS).unwrap() {
+            return Err(Error::UnexpectedEther(UnexpectedEther { sender }));
+        }
+        Ok(())
+    }
+
+    fn _set_token_config(&mut self, token: Address, scale_factor: U256, decimals: u8) -> Result<(), Error> {
+        let current_scale_factor = self.token_configs.get(token).scale_factor.get();
+        // Encrypted balances are denominated in scaled units: repricing them is not allowed
+        if current_scale_factor != scale_factor && !self.total_deposited.get(token).is_zero() {
+            return Err(Error::ScaleFactorLocked(ScaleFactorLocked { token }));
+        }
+
+        // First configuration of this token
+        if current_scale_factor.is_zero() {
+            self.token_list.push(token);
+        }
+
+        let mut config = self.token_configs.setter(token);
+        config.scale_factor.set(scale_factor);
+        config.decimals.set(U8::from(decimals));
+        log(self.vm(), TokenConfigUpdated {
+            token,
+            scale_factor,
+            decimals,
+        });
+        Ok(())
+    }
+
+    fn _set_supported_token(&mut self, token: Address, allowed: bool) {
+        self.token_configs.setter(token).enabled.set(allowed);
+        log(self.vm(), TokenAllowlistUpdated { token, allowed });
+    }
+
     // Owner-only
-    fn _only_owner(&self) -> Result<(), Vec<u8>> {
-        if self.vm().msg_sender() != self.owner.get() {
-            return Err("Not owner".into());
+    fn _only_owner(&self) -> Result<(), Error> {
+        let account = self.vm().msg_sender();
+        if account != self.owner.get() {
+            return Err(Error::NotOwner(NotOwner { account }));
+        }
+        Ok(())
+    }
+
+    fn _only_role(&self, role: FixedBytes<32>) -> Result<(), Error> {
+        let account = self.vm().msg_sender();
+        if !self.has_role(role, account) {
+            return Err(Error::MissingRole(MissingRole { role, account }));
+        }
+        Ok(())
+    }
+
+    // Owner or PAUSER_ROLE
+    fn _only_pauser(&self) -> Result<(), Error> {
+        if self.vm().msg_sender() == self.owner.get() {
+            return Ok(());
+        }
+        self._only_role(PAUSER_ROLE)
+    }
+
+    // Owner or COMPLIANCE_ROLE
+    fn _only_compliance(&self) -> Result<(), Error> {
+        if self.vm().msg_sender() == self.owner.get() {
+            return Ok(());
+        }
+        self._only_role(COMPLIANCE_ROLE)
+    }
+
+    /// Funds may move from `from` to `to`: neither is frozen and the policy contract,
+    /// if any, allows it. A policy that reverts or returns anything but `true` denies.
+    fn _check_compliance(&self, from: Address, to: Address, token: Address) -> Result<(), Error> {
+        for account in [from, to] {
+            if self.frozen.get(account) {
+                return Err(Error::FrozenAccount(FrozenAccount { account }));
+            }
+        }
+
+        let policy = self.policy.get();
+        if policy == Address::ZERO {
+            return Ok(());
+        }
+        let calldata = isAllowedCall { from, to, token }.abi_encode();
+        let data = self
+            .vm()
+            .static_call(&Call::new(), policy, &calldata)
+            .map_err(|e| Error::PolicyCallReverted(PolicyCallReverted { policy, data: Vec::<u8>::from(e).into() }))?;
+        let allowed = isAllowedCall::abi_decode_returns(&data, true).is_ok_and(|r| r._0);
+        if !allowed {
+            return Err(Error::PolicyDenied(PolicyDenied { from, to, token }));
+        }
+        Ok(())
+    }
+
+    fn _when_not_paused(&self, operation: u8) -> Result<(), Error> {
+        if self.paused_all.get() {
+            return Err(Error::EnforcedPause(EnforcedPause {}));
+        }
+        if operation != OP_ALL && self.paused_operations.get(U8::from(operation)) {
+            return Err(Error::OperationPaused(OperationPaused { operation }));
+        }
+        Ok(())
+    }
+
+    fn _when_token_not_paused(&self, token: Address) -> Result<(), Error> {
+        if self.is_token_paused(token) {
+            return Err(Error::TokenPaused(TokenPaused { token }));
+        }
+        Ok(())
+    }
+
+    /// Set a pause flag: global (OP_ALL, no token), one operation, or one token.
+    /// Emits only when the flag actually changes.
+    fn _set_paused(&mut self, operation: u8, token: Address, paused: bool) {
+        let was_paused = if token != Address::ZERO {
+            self.is_token_paused(token)
+        } else if operation == OP_ALL {
+            self.paused_all.get()
+        } else {
+            self.paused_operations.get(U8::from(operation))
+        };
+        if was_paused == paused {
+            return;
+        }
+
+        if token != Address::ZERO {
+            self.token_configs.setter(token).paused.set(paused);
+        } else if operation == OP_ALL {
+            self.paused_all.set(paused);
+        } else {
+            self.paused_operations.setter(U8::from(operation)).set(paused);
+        }
+
+        let account = self.vm().msg_sender();
+        if paused {
+            log(self.vm(), Paused { account, operation, token });
+        } else {
+            log(self.vm(), Unpaused { account, operation, token });
+        }
+    }
+
+    fn _set_owner(&mut self, new_owner: Address) {
+        let previous_owner = self.owner.get();
+        self.owner.set(new_owner);
+        self.pending_owner.set(Address::ZERO);
+        log(self.vm(), OwnershipTransferred { previous_owner, new_owner });
+    }
+
+    fn _grant_role(&mut self, role: FixedBytes<32>, account: Address) {
+        if self.has_role(role, account) {
+            return;
+        }
+        self.roles.setter(role).setter(account).set(true);
+        let sender = self.vm().msg_sender();
+        log(self.vm(), RoleGranted { role, account, sender });
+    }
+
+    fn _revoke_role(&mut self, role: FixedBytes<32>, account: Address) {
+        if !self.has_role(role, account) {
+            return;
+        }
+        self.roles.setter(role).setter(account).set(false);
+        let sender = self.vm().msg_sender();
+        log(self.vm(), RoleRevoked { role, account, sender });
+    }
+
+    /// Nullifier of a proof: keccak256(verifier || proof || proof_inputs).
+    fn _nullifier(verifier: Address, proof: &[u8], proof_inputs: &[u8]) -> FixedBytes<32> {
+        keccak256([verifier.as_slice(), proof, proof_inputs].concat())
+    }
+
+    /// Mark a proof as used, failing if it was already accepted before.
+    fn _spend_nullifier(
+        &mut self,
+        verifier: Address,
+        proof: &[u8],
+        proof_inputs: &[u8],
+    ) -> Result<(), Error> {
+        let nullifier = Self::_nullifier(verifier, proof, proof_inputs);
+        if self.nullifiers.get(nullifier) {
+            return Err(Error::NullifierAlreadyUsed(NullifierAlreadyUsed { nullifier }));
+        }
+        self.nullifiers.setter(nullifier).set(true);
+
+        log(self.vm(), NullifierSpent { nullifier });
+        Ok(())
+    }
+
+    /// Read the encrypted balance for a user and token.
+    fn _get_balance(&self, token: Address, user: Address) -> Ciphertext {
+        let (t, u) = balance_key(token, user);
+        if self.balance_versions.get(t).get(u).is_zero() {
+            return self._zero_balance(user);
+        }
+        Ciphertext {
+            x1: Point { x: self.balances_x1.get(t).get(u).into(), y: self.balances_y1.get(t).get(u).into() },
+            x2: Point { x: self.balances_x2.get(t).get(u).into(), y: self.balances_y2.get(t).get(u).into() },
+        }
+    }
+
+    /// Canonical encryption of 0 under the user's key: (G, pk) with randomness 1.
+    /// All-zero for unregistered users.
+    fn _zero_balance(&self, user: Address) -> Ciphertext {
+        let pk = self._get_user_pk(user);
+        if pk == [0u8; 64] {
+            return Ciphertext::zero();
+        }
+        Ciphertext {
+            x1: Point { x: G_GENERATOR_X, y: G_GENERATOR_Y },
+            x2: Point::from_bytes(pk),
+        }
+    }
+
+    /// Key epoch of a balance. Balances never written read as encryptions of 0 under
+    /// the current key.
+    fn _balance_epoch(&self, token: Address, user: Address) -> U256 {
+        let (t, u) = balance_key(token, user);
+        if self.balance_versions.get(t).get(u).is_zero() {
+            return self.key_epochs.get(user);
+        }
+        self.balance_epochs.get(t).get(u)
+    }
+
+    /// Public key of `user` at `key_epoch`: the current key, a retired one, or all-zero.
+    fn _user_pk_at(&self, user: Address, key_epoch: U256) -> [u8; 64] {
+        let current_epoch = self.key_epochs.get(user);
+        if key_epoch == current_epoch {
+            return self._get_user_pk(user);
+        }
+        let mut pk = [0u8; 64];
+        if key_epoch < current_epoch {
+            pk[..32].copy_from_slice(self.retired_pk_x.get(user).get(key_epoch).as_slice());
+            pk[32..].copy_from_slice(self.retired_pk_y.get(user).get(key_epoch).as_slice());
+        }
+        pk
+    }
+
+    /// Read the raw pending (incoming, not yet applied) ciphertext for a user and token.
+    /// It is under the key of `pending_epochs`; see `_pending_at`.
+    fn _get_pending(&self, token: Address, user: Address) -> Ciphertext {
+        let (t, u) = balance_key(token, user);
+        Ciphertext {
+            x1: Point { x: self.pending_x1.get(t).get(u).into(), y: self.pending_y1.get(t).get(u).into() },
+            x2: Point { x: self.pending_x2.get(t).get(u).into(), y: self.pending_y2.get(t).get(u).into() },
+        }
+    }
+
+    fn _set_pending(&mut self, token: Address, user: Address, ct: &Ciphertext) {
+        let (t, u) = balance_key(token, user);
+        self.pending_x1.setter(t).setter(u).set(FixedBytes::from(ct.x1.x));
+        self.pending_y1.setter(t).setter(u).set(FixedBytes::from(ct.x1.y));
+        self.pending_x2.setter(t).setter(u).set(FixedBytes::from(ct.x2.x));
+        self.pending_y2.setter(t).setter(u).set(FixedBytes::from(ct.x2.y));
+    }
+
+    /// Pending ciphertext under the key of `key_epoch`: the pending balance if it was
+    /// received under that key, otherwise the bucket kept for a retired key.
+    fn _pending_at(&self, token: Address, user: Address, key_epoch: U256) -> Ciphertext {
+        let (t, u) = balance_key(token, user);
+        if self.pending_epochs.get(t).get(u) == key_epoch {
+            return self._get_pending(token, user);
+        }
+        if key_epoch >= self.key_epochs.get(user) {
+            return Ciphertext::zero();
+        }
+        let stale = self.stale_pending.get(stale_pending_key(token, user, key_epoch));
+        Ciphertext {
+            x1: Point { x: stale.x1.get().into(), y: stale.y1.get().into() },
+            x2: Point { x: stale.x2.get().into(), y: stale.y2.get().into() },
+        }
+    }
+
+    fn _set_stale_pending(&mut self, token: Address, user: Address, key_epoch: U256, ct: &Ciphertext) {
+        let mut stale = self.stale_pending.setter(stale_pending_key(token, user, key_epoch));
+        stale.x1.set(FixedBytes::from(ct.x1.x));
+        stale.y1.set(FixedBytes::from(ct.x1.y));
+        stale.x2.set(FixedBytes::from(ct.x2.x));
+        stale.y2.set(FixedBytes::from(ct.x2.y));
+    }
+
+    /// Empty the pending ciphertext that `_pending_at` reads for `key_epoch`.
+    fn _clear_pending_at(&mut self, token: Address, user: Address, key_epoch: U256) {
+        let (t, u) = balance_key(token, user);
+        if self.pending_epochs.get(t).get(u) == key_epoch {
+            self._set_pending(token, user, &Ciphertext::zero());
+        } else {
+            self._set_stale_pending(token, user, key_epoch, &Ciphertext::zero());
+        }
+    }
+
+    /// Homomorphically add an incoming amount ciphertext, encrypted under the user's
+    /// current key, to the pending balance, returning the new pending ciphertext.
+    /// Pending received under a retired key first moves to that key's bucket.
+    fn _add_pending(&mut self, token: Address, user: Address, amount: &Ciphertext) -> Ciphertext {
+        let (t, u) = balance_key(token, user);
+        let key_epoch = self.key_epochs.get(user);
+        let pending_epoch = self.pending_epochs.get(t).get(u);
+        if pending_epoch != key_epoch {
+            let stale = self._get_pending(token, user);
+            if stale != Ciphertext::zero() {
+                self._set_stale_pending(token, user, pending_epoch, &stale);
+                self._set_pending(token, user, &Ciphertext::zero());
+            }
+            self.pending_epochs.setter(t).setter(u).set(key_epoch);
+        }
+
+        let pending = grumpkin::add_ciphertexts(&self._get_pending(token, user), amount);
+        self._set_pending(token, user, &pending);
+        pending
+    }
+
+    /// Read an allowance as (under the owner's key, under the spender's key).
+    /// Never-approved allowances read as encryptions of 0, like balances.
+    fn _get_allowance(&self, token: Address, owner: Address, spender: Address) -> (Ciphertext, Ciphertext) {
+        let allowance = self.allowances.get(allowance_key(token, owner, spender));
+        if allowance.version.get().is_zero() {
+            return (self._zero_balance(owner), self._zero_balance(spender));
+        }
+        (
+            Ciphertext {
+                x1: Point { x: allowance.owner_x1.get().into(), y: allowance.owner_y1.get().into() },
+                x2: Point { x: allowance.owner_x2.get().into(), y: allowance.owner_y2.get().into() },
+            },
+            Ciphertext {
+                x1: Point { x: allowance.spender_x1.get().into(), y: allowance.spender_y1.get().into() },
+                x2: Point { x: allowance.spender_x2.get().into(), y: allowance.spender_y2.get().into() },
+            },
+        )
+    }
+
+    /// Whether the last proof written to an allowance showed a non-zero amount.
+    fn _allowance_is_open(&self, token: Address, owner: Address, spender: Address) -> bool {
+        self.allowances.get(allowance_key(token, owner, spender)).open.get()
+    }
+
+    /// Refuse an auditor key for `token` while allowances on it are open, since they
+    /// could no longer be spent.
+    fn _check_no_token_allowances(&self, token: Address) -> Result<(), Error> {
+        let count = self.token_open_allowances.get(token);
+        if !count.is_zero() {
+            return Err(Error::TokenHasOpenAllowances(TokenHasOpenAllowances { token, count }));
         }
         Ok(())
     }
 
-    /// Set encrypted balance for a user and token.
-    fn _set_balance(&mut self, token: Address, user: Address, ct: &Ciphertext) {
+    /// Set both sides of an allowance, returning its new version. `open` comes from
+    /// the proof and keeps the owner's and the token's open allowance counts up to date.
+    fn _set_allowance(
+        &mut self,
+        token: Address,
+        owner: Address,
+        spender: Address,
+        owner_allowance: &Ciphertext,
+        spender_allowance: &Ciphertext,
+        open: bool,
+    ) -> U256 {
+        if self._allowance_is_open(token, owner, spender) != open {
+            let count = self.open_allowances.get(owner);
+            let count = if open { count + U256::from(1) } else { count - U256::from(1) };
+            self.open_allowances.setter(owner).set(count);
+            let count = self.token_open_allowances.get(token);
+            let count = if open { count + U256::from(1) } else { count - U256::from(1) };
+            self.token_open_allowances.setter(token).set(count);
+        }
+
+        let mut allowance = self.allowances.setter(allowance_key(token, owner, spender));
+        let version = allowance.version.get() + U256::from(1);
+        allowance.version.set(version);
+        allowance.open.set(open);
+        allowance.owner_x1.set(FixedBytes::from(owner_allowance.x1.x));
+        allowance.owner_y1.set(FixedBytes::from(owner_allowance.x1.y));
+        allowance.owner_x2.set(FixedBytes::from(owner_allowance.x2.x));
+        allowance.owner_y2.set(FixedBytes::from(owner_allowance.x2.y));
+        allowance.spender_x1.set(FixedBytes::from(spender_allowance.x1.x));
+        allowance.spender_y1.set(FixedBytes::from(spender_allowance.x1.y));
+        allowance.spender_x2.set(FixedBytes::from(spender_allowance.x2.x));
+        allowance.spender_y2.set(FixedBytes::from(spender_allowance.x2.y));
+        version
+    }
+
+    /// Set encrypted balance for a user and token, returning its new version.
+    /// A first write happens under the user's current key.
+    fn _set_balance(&mut self, token: Address, user: Address, ct: &Ciphertext) -> U256 {
         let (t, u) = balance_key(token, user);
+        let version = self.balance_versions.get(t).get(u) + U256::from(1);
+        if version == U256::from(1) {
+            self.balance_epochs.setter(t).setter(u).set(self.key_epochs.get(user));
+        }
+        self.balance_versions.setter(t).setter(u).set(version);
         self.balances_x1
             .setter(t)
             .setter(u)
@@ -432,18 +2791,177 @@ impl ConfidentialERC20 {
             .setter(t)
             .setter(u)
             .set(FixedBytes::from(ct.x2.y));
+        version
+    }
+
+    fn _register_verifier(&mut self, circuit: u8, version: u32, verifier: Address) -> Result<(), Error> {
+        if !(CIRCUIT_DEPOSIT..=CIRCUIT_REKEY).contains(&circuit) {
+            return Err(Error::InvalidCircuit(InvalidCircuit { circuit }));
+        }
+        if verifier == Address::ZERO {
+            return Err(Error::InvalidVerifier(InvalidVerifier { verifier }));
+        }
+        if version == 0 || self.get_verifier(circuit, version).0 != Address::ZERO {
+            return Err(Error::InvalidVerifierVersion(InvalidVerifierVersion { circuit, version }));
+        }
+
+        self.verifiers.setter(U8::from(circuit)).setter(U32::from(version)).verifier.set(verifier);
+        if version > self.current_verifier_version(circuit) {
+            self.current_verifier_versions.setter(U8::from(circuit)).set(U32::from(version));
+        }
+        log(self.vm(), VerifierRegistered { circuit, version, verifier });
+        Ok(())
+    }
+
+    /// Queue a verifier registration; version 0 registers the next version when executed.
+    fn _queue_verifier(&mut self, circuit: u8, version: u32, verifier: Address) -> Result<(), Error> {
+        if !(CIRCUIT_DEPOSIT..=CIRCUIT_REKEY).contains(&circuit) {
+            return Err(Error::InvalidCircuit(InvalidCircuit { circuit }));
+        }
+        if verifier == Address::ZERO {
+            return Err(Error::InvalidVerifier(InvalidVerifier { verifier }));
+        }
+        if version != 0 && self.get_verifier(circuit, version).0 != Address::ZERO {
+            return Err(Error::InvalidVerifierVersion(InvalidVerifierVersion { circuit, version }));
+        }
+        self._queue_change(CHANGE_VERIFIER, verifier, U256::from(circuit), U256::from(version), U256::ZERO)
+    }
+
+    /// Record a governance change, executable once the timelock delay has passed.
+    fn _queue_change(
+        &mut self,
+        kind: u8,
+        target: Address,
+        arg0: U256,
+        arg1: U256,
+        arg2: U256,
+    ) -> Result<(), Error> {
+        let id = self.change_count.get();
+        self.change_count.set(id + U256::from(1));
+        let delay = self.timelock_delay.get();
+        let eta = U256::from(self.vm().block_timestamp()) + delay;
+
+        let mut change = self.changes.setter(id);
+        change.kind.set(U8::from(kind));
+        change.target.set(target);
+        change.arg0.set(arg0);
+        change.arg1.set(arg1);
+        change.arg2.set(arg2);
+        change.eta.set(eta);
+        change.queued.set(true);
+        log(self.vm(), ChangeQueued { id, kind, target, arg0, arg1, arg2, eta });
+        Ok(())
+    }
+
+    fn _execute_change(&mut self, id: U256) -> Result<(), Error> {
+        let change = self.changes.get(id);
+        let kind = change.kind.get().to::<u8>();
+        let target = change.target.get();
+        let (arg0, arg1, arg2) = (change.arg0.get(), change.arg1.get(), change.arg2.get());
+        self.changes.setter(id).queued.set(false);
+
+        match kind {
+            CHANGE_VERIFIER => {
+                let (circuit, version) = (arg0.to::<u8>(), arg1.to::<u32>());
+                if version == 0 {
+                    self._register_next_verifier(circuit, target)?;
+                } else {
+                    self._register_verifier(circuit, version, target)?;
+                }
+            }
+            CHANGE_TOKEN_REMOVAL => self._set_supported_token(target, false),
+            CHANGE_FEE_CONFIG => {
+                let mut config = self.token_configs.setter(target);
+                config.fee_bps.set(arg0);
+                config.flat_fee.set(arg1);
+                config.fee_cap.set(arg2);
+                log(self.vm(), FeeConfigUpdated { token: target, fee_bps: arg0, flat_fee: arg1, fee_cap: arg2 });
+            }
+            CHANGE_TIMELOCK_DELAY => {
+                let previous_delay = self.timelock_delay.get();
+                self.timelock_delay.set(arg0);
+                log(self.vm(), TimelockDelayUpdated { previous_delay, new_delay: arg0 });
+            }
+            CHANGE_GUARDIAN => {
+                let previous_guardian = self.guardian.get();
+                self.guardian.set(target);
+                log(self.vm(), GuardianUpdated { previous_guardian, new_guardian: target });
+            }
+            CHANGE_TOKEN_CONFIG => self._set_token_config(target, arg0, arg1.to::<u8>())?,
+            CHANGE_AUDITOR_KEY => {
+                // Allowances may have been opened while the change was queued
+                if !arg0.is_zero() || !arg1.is_zero() {
+                    self._check_no_token_allowances(target)?;
+                }
+                let (x, y) = (arg0.to_be_bytes::<32>(), arg1.to_be_bytes::<32>());
+                let mut config = self.token_configs.setter(target);
+                config.auditor_x.set(FixedBytes::from(x));
+                config.auditor_y.set(FixedBytes::from(y));
+                log(self.vm(), AuditorKeyUpdated { token: target, auditor_pk: [x, y].concat().into() });
+            }
+            CHANGE_TRANSFER_MODE => {
+                let (fee_on_transfer, rebasing) = (arg0.bit(0), arg0.bit(1));
+                let mut config = self.token_configs.setter(target);
+                config.fee_on_transfer.set(fee_on_transfer);
+                config.rebasing.set(rebasing);
+                config.transfer_tolerance.set(arg1);
+                log(self.vm(), TokenTransferModeUpdated { token: target, fee_on_transfer, rebasing, transfer_tolerance: arg1 });
+            }
+            _ => {}
+        }
+
+        log(self.vm(), ChangeExecuted { id, kind });
+        Ok(())
+    }
+
+    // Authority that may queue and execute a kind of change
+    fn _only_change_admin(&self, kind: u8) -> Result<(), Error> {
+        match kind {
+            CHANGE_VERIFIER => self._only_role(VERIFIER_ADMIN_ROLE),
+            CHANGE_TOKEN_REMOVAL | CHANGE_TOKEN_CONFIG | CHANGE_TRANSFER_MODE => self._only_role(TOKEN_ADMIN_ROLE),
+            _ => self._only_owner(),
+        }
+    }
+
+    fn _register_next_verifier(&mut self, circuit: u8, verifier: Address) -> Result<(), Error> {
+        let version = self.current_verifier_version(circuit) + 1;
+        self._register_verifier(circuit, version, verifier)
+    }
+
+    fn _current_verifier(&self, circuit: u8) -> Address {
+        self.get_verifier(circuit, self.current_verifier_version(circuit)).0
+    }
+
+    /// Verifier of `circuit` for proofs built against `version` (0 = current version),
+    /// unless the version is unknown or past its cut-off block.
+    fn _verifier(&self, circuit: u8, version: u32) -> Result<Address, Error> {
+        let version = if version == 0 { self.current_verifier_version(circuit) } else { version };
+        let (verifier, cutoff_block) = self.get_verifier(circuit, version);
+        if verifier == Address::ZERO {
+            return Err(Error::UnknownVerifierVersion(UnknownVerifierVersion { circuit, version }));
+        }
+        if !cutoff_block.is_zero() && U256::from(self.vm().block_number()) > cutoff_block {
+            return Err(Error::VerifierVersionDeprecated(VerifierVersionDeprecated { circuit, version, cutoff_block }));
+        }
+        Ok(verifier)
     }
 
     /// Verify a Noir proof.
     ///
     /// All cryptographic relations between ciphertexts & amounts live inside `proof_inputs`
-    /// and are proved in Verifier before using them.
+    /// and are proved in Verifier before using them. The verifier must return exactly one
+    /// ABI-encoded `true`; anything else is rejected with a distinct `VerifyProofError`.
     fn _verify_proof(
         &self,
         proof_inputs: &[u8],
         proof: AbiBytes,
         verifier_address: Address,
-    ) -> Result<(), Vec<u8>> {
+    ) -> Result<(), VerifyProofError> {
+        // A static call to an address without code succeeds with empty return data
+        if self.vm().code_size(verifier_address) == 0 {
+            return Err(VerifyProofError::NotAContract);
+        }
+
         let mut public_inputs_vec: Vec<FixedBytes<32>> = Vec::new();
 
         for chunk in proof_inputs.chunks(32) {
@@ -452,42 +2970,163 @@ impl ConfidentialERC20 {
             public_inputs_vec.push(FixedBytes::<32>::from(buf));
         }
     
-        // Typed call to verifier.verify(bytes,bytes)
+        // Typed call to verifier.verify(bytes,bytes32[])
         let calldata = verifyCall {
             proof: Bytes::from(proof.to_vec()),
             publicInputs: public_inputs_vec,
         }.abi_encode();
 
-        unsafe {
-            let res: Result<Vec<u8>, Vec<u8>> =RawCall::new_static().call(verifier_address, &calldata);
-            let data = match res {
-                Ok(data) => data,
-                Err(_data) => panic!("Proof verification failed"),
-            };
+        let data = self
+            .vm()
+            .static_call(&Call::new(), verifier_address, &calldata)
+            .map_err(|e| VerifyProofError::CallReverted(e.into()))?;
+
+        if data.len() != 32 {
+            return Err(VerifyProofError::BadReturnLength(data.len()));
         }
-    
+
+        // Strict decoding rejects anything other than a canonical 0 or 1 word
+        let verified = verifyCall::abi_decode_returns(&data, true)
+            .map_err(|_| VerifyProofError::NonCanonicalBool(data[..32].try_into().unwrap()))?
+            ._0;
+        if !verified {
+            return Err(VerifyProofError::ReturnedFalse);
+        }
+
         Ok(())
     }
 
+    /// Tokens owed to encrypted balances plus unclaimed fees
+    fn _custody_liability(&self, token: Address) -> U256 {
+        self.total_deposited.get(token).saturating_sub(self.total_withdrawn.get(token)) + self.accrued_fees.get(token)
+    }
+
+    /// Add a deposit/withdraw fee to the claimable ledger.
+    fn _accrue_fee(&mut self, token: Address, account: Address, fee: U256) {
+        if fee.is_zero() {
+            return;
+        }
+        let total_accrued = self.accrued_fees.get(token) + fee;
+        self.accrued_fees.setter(token).set(total_accrued);
+        log(self.vm(), FeesAccrued { token, account, fee, total_accrued });
+    }
+
     /// Plain ERC-20 transfer using typed sol! call
-    fn _transfer(&self, token: Address, to: Address, amount: U256) -> Result<(), Vec<u8>> {
+    fn _transfer(&self, token: Address, to: Address, amount: U256) -> Result<(), Error> {
         let calldata = transferCall { to, amount }.abi_encode();
     
-        // raw CALL to token
-        let res = unsafe {
-            RawCall::new()
-                .call(token, &calldata)?
-        };
+        // CALL to token
+        let res = self
+            .vm()
+            .call(&Call::new(), token, &calldata)
+            .map_err(|e| Error::Erc20CallReverted(Erc20CallReverted { token, data: Vec::<u8>::from(e).into() }))?;
     
         // Standard ERC-20 convention: if it returns a bool, check it.
         // If it returns nothing, treat as success.
         if res.len() >= 32 && res[31] == 0 {
-            return Err("ERC20 transfer failed".into());
+            return Err(Error::Erc20TransferFailed(Erc20TransferFailed { token, to, amount }));
         }
     
         Ok(())
     }    
 
+    /// `balanceOf(this)` of a token; `None` when the token returns no data and
+    /// custody can't be measured
+    fn _custody_balance(&self, token: Address) -> Result<Option<U256>, Error> {
+        let calldata = balanceOfCall { account: self.vm().contract_address() }.abi_encode();
+        let res = self
+            .vm()
+            .static_call(&Call::new(), token, &calldata)
+            .map_err(|e| Error::Erc20CallReverted(Erc20CallReverted { token, data: Vec::<u8>::from(e).into() }))?;
+        if res.len() < 32 {
+            return Ok(None);
+        }
+        Ok(Some(U256::from_be_slice(&res[..32])))
+    }
+
+    /// Check that custody moved by `expected` (into it on deposits, out of it on
+    /// withdrawals). Only rebasing tokens get their transfer tolerance.
+    fn _check_custody_delta(
+        &self,
+        token: Address,
+        before: Option<U256>,
+        expected: U256,
+        is_deposit: bool,
+    ) -> Result<(), Error> {
+        let (Some(before), Some(after)) = (before, self._custody_balance(token)?) else {
+            return Ok(());
+        };
+        let actual = if is_deposit { after.saturating_sub(before) } else { before.saturating_sub(after) };
+        let gap = if actual > expected { actual - expected } else { expected - actual };
+        let config = self.token_configs.get(token);
+        let tolerance = if config.rebasing.get() { config.transfer_tolerance.get() } else { U256::ZERO };
+        if gap > tolerance {
+            return Err(Error::BalanceDeltaMismatch(BalanceDeltaMismatch { token, expected, actual }));
+        }
+        Ok(())
+    }
+
+    /// Wrap `amount` of the attached ETH into WETH
+    fn _wrap_eth(&self, weth: Address, amount: U256) -> Result<(), Error> {
+        let calldata = depositCall {}.abi_encode();
+        self.vm()
+            .call(&Call::new().value(amount), weth, &calldata)
+            .map_err(|e| Error::Erc20CallReverted(Erc20CallReverted { token: weth, data: Vec::<u8>::from(e).into() }))?;
+        Ok(())
+    }
+
+    /// Unwrap `amount` of WETH and send it to `to` as native ETH
+    fn _unwrap_eth(&self, weth: Address, to: Address, amount: U256) -> Result<(), Error> {
+        let calldata = withdrawCall { amount }.abi_encode();
+        self.vm()
+            .call(&Call::new(), weth, &calldata)
+            .map_err(|e| Error::Erc20CallReverted(Erc20CallReverted { token: weth, data: Vec::<u8>::from(e).into() }))?;
+        self.vm()
+            .transfer_eth(to, amount)
+            .map_err(|_| Error::EthTransferFailed(EthTransferFailed { to, amount }))
+    }
+
+    /// EIP-2612 permit for this contract. A revert is ignored: the permit may have been
+    /// front-run, and `transferFrom` still fails if no allowance was granted.
+    #[allow(clippy::too_many_arguments)]
+    fn _permit(
+        &self,
+        token: Address,
+        owner: Address,
+        value: U256,
+        deadline: U256,
+        v: u8,
+        r: FixedBytes<32>,
+        s: FixedBytes<32>,
+    ) {
+        let spender = self.vm().contract_address();
+        let calldata = permitCall { owner, spender, value, deadline, v, r, s }.abi_encode();
+        let _ = self.vm().call(&Call::new(), token, &calldata);
+    }
+
+    /// Pull `amount` of `token` from `owner` through a Permit2 signature transfer
+    fn _permit2_transfer_from(
+        &self,
+        token: Address,
+        owner: Address,
+        amount: U256,
+        nonce: U256,
+        deadline: U256,
+        signature: AbiBytes,
+    ) -> Result<(), Error> {
+        let calldata = permitTransferFromCall {
+            permit: PermitTransferFrom { permitted: TokenPermissions { token, amount }, nonce, deadline },
+            transferDetails: SignatureTransferDetails { to: self.vm().contract_address(), requestedAmount: amount },
+            owner,
+            signature: Bytes::from(signature.to_vec()),
+        }
+        .abi_encode();
+        self.vm()
+            .call(&Call::new(), Address::from_str(PERMIT2_ADDRESS).unwrap(), &calldata)
+            .map_err(|e| Error::Permit2CallReverted(Permit2CallReverted { data: Vec::<u8>::from(e).into() }))?;
+        Ok(())
+    }
+
     /// Plain ERC-20 transferFrom using typed sol! call
     fn _transfer_from(
         &self,
@@ -495,16 +3134,16 @@ impl ConfidentialERC20 {
         from: Address,
         to: Address,
         amount: U256,
-    ) -> Result<(), Vec<u8>> {
+    ) -> Result<(), Error> {
         let calldata = transferFromCall { from, to, amount }.abi_encode();
 
-        let res = unsafe {
-            RawCall::new()
-                .call(token, &calldata)?
-        };
+        let res = self
+            .vm()
+            .call(&Call::new(), token, &calldata)
+            .map_err(|e| Error::Erc20CallReverted(Erc20CallReverted { token, data: Vec::<u8>::from(e).into() }))?;
     
         if res.len() >= 32 && res[31] == 0 {
-            return Err("ERC20 transferFrom failed".into());
+            return Err(Error::Erc20TransferFromFailed(Erc20TransferFromFailed { token, from, amount }));
         }
     
         Ok(())
@@ -520,60 +3159,174 @@ impl ConfidentialERC20 {
     /// Parse public inputs into DepositWidthdrawProofInputs struct.
     fn _decode_deposit_withdraw_proof_inputs(
         &self,
-        proof_inputs: [u8; 416],
-    ) -> Result<DepositWidthdrawProofInputs, Vec<u8>> {
-    
-        let user_pubkey: [u8; 64] = proof_inputs[..64]
-            .try_into()
-            .map_err(|_| "bad pubkey slice".as_bytes().to_vec())?;
-    
-        let current_slice: [u8; 128] = proof_inputs[64..192]
-            .try_into()
-            .map_err(|_| "bad current_balance slice".as_bytes().to_vec())?;
-    
-        let current_balance = self._decode_ciphertext(current_slice);
-    
-        // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
-        let user_address = Address::from_slice(&proof_inputs[204..224]);
-        let token = Address::from_slice(&proof_inputs[236..256]);
-
-        let amount_bytes: [u8; 32] = proof_inputs[256..288]
-            .try_into()
-            .map_err(|_| "bad amount slice".as_bytes().to_vec())?;
-        let amount = U256::from_be_bytes(amount_bytes);
-
-        let new_slice: [u8; 128] = proof_inputs[288..416]
-            .try_into()
-            .map_err(|_| "bad new_balance slice".as_bytes().to_vec())?;
-
-        let new_balance = self._decode_ciphertext(new_slice);
-    
-        Ok(DepositWidthdrawProofInputs {
-            user_pubkey,
-            current_balance,
-            new_balance,
-            user_address,
-            token,
-            amount,
-        })
+        proof_inputs: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN],
+    ) -> DepositWidthdrawProofInputs {
+        DepositWidthdrawProofInputs {
+            user_pubkey: proof_inputs[..64].try_into().unwrap(),
+            current_balance: self._decode_ciphertext(proof_inputs[64..192].try_into().unwrap()),
+            // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
+            counterparty: Address::from_slice(&proof_inputs[204..224]),
+            token: Address::from_slice(&proof_inputs[236..256]),
+            amount: U256::from_be_slice(&proof_inputs[256..288]),
+            domain: proof_inputs[288..320].try_into().unwrap(),
+            balance_version: U256::from_be_slice(&proof_inputs[320..352]),
+            auditor_pubkey: proof_inputs[352..416].try_into().unwrap(),
+            new_balance: self._decode_ciphertext(proof_inputs[416..544].try_into().unwrap()),
+            auditor_amount: self._decode_ciphertext(proof_inputs[544..672].try_into().unwrap()),
+        }
     }
 
     /// Parse public inputs into TransferConfidentialProofInputs struct.
-    fn _decode_transfer_confidential_proof_inputs(&self, proof_inputs: [u8; 704]) -> TransferConfidentialProofInputs {
+    fn _decode_transfer_confidential_proof_inputs(
+        &self,
+        proof_inputs: [u8; TRANSFER_INPUTS_LEN],
+    ) -> TransferConfidentialProofInputs {
         TransferConfidentialProofInputs {
             // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
             receiver_address: Address::from_slice(&proof_inputs[12..32]),
             receiver_pubkey: proof_inputs[32..96].try_into().unwrap(),
-            receiver_current_balance: self._decode_ciphertext(proof_inputs[96..224].try_into().unwrap()),
-            sender_pubkey: proof_inputs[224..288].try_into().unwrap(),
-            sender_current_balance: self._decode_ciphertext(proof_inputs[288..416].try_into().unwrap()),
+            sender_pubkey: proof_inputs[96..160].try_into().unwrap(),
+            sender_current_balance: self._decode_ciphertext(proof_inputs[160..288].try_into().unwrap()),
             // Addresses only takes 20 bytes, so we need to trim
-            token: Address::from_slice(&proof_inputs[428..448]),
+            token: Address::from_slice(&proof_inputs[300..320]),
+            domain: proof_inputs[320..352].try_into().unwrap(),
+            sender_balance_version: U256::from_be_slice(&proof_inputs[352..384]),
+            auditor_pubkey: proof_inputs[384..448].try_into().unwrap(),
             sender_new_balance: self._decode_ciphertext(proof_inputs[448..576].try_into().unwrap()),
-            receiver_new_balance: self._decode_ciphertext(proof_inputs[576..704].try_into().unwrap()),
+            receiver_amount: self._decode_ciphertext(proof_inputs[576..704].try_into().unwrap()),
+            auditor_amount: self._decode_ciphertext(proof_inputs[704..832].try_into().unwrap()),
+        }
+    }
+
+    /// Parse public inputs into BatchTransferConfidentialProofInputs struct.
+    ///
+    /// Same fields as a single transfer, with the receiver slots moved to the front and
+    /// their amounts to the back:
+    /// [0..96N) receivers (address, pubkey), then sender_pubkey, sender_current_balance,
+    /// token, domain, sender_balance_version, sender_new_balance, [.. + 128N) amounts.
+    fn _decode_batch_transfer_confidential_proof_inputs(
+        &self,
+        proof_inputs: [u8; BATCH_TRANSFER_INPUTS_LEN],
+    ) -> BatchTransferConfidentialProofInputs {
+        let sender_at = 96 * MAX_BATCH_RECEIVERS;
+        let amounts_at = sender_at + 416;
+        let receivers = (0..MAX_BATCH_RECEIVERS)
+            .map(|i| {
+                let receiver_at = 96 * i;
+                let amount_at = amounts_at + 128 * i;
+                BatchTransferReceiver {
+                    // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
+                    receiver_address: Address::from_slice(&proof_inputs[receiver_at + 12..receiver_at + 32]),
+                    receiver_pubkey: proof_inputs[receiver_at + 32..receiver_at + 96].try_into().unwrap(),
+                    receiver_amount: self._decode_ciphertext(proof_inputs[amount_at..amount_at + 128].try_into().unwrap()),
+                }
+            })
+            .filter(|receiver| receiver.receiver_address != Address::ZERO)
+            .collect();
+
+        BatchTransferConfidentialProofInputs {
+            receivers,
+            sender_pubkey: proof_inputs[sender_at..sender_at + 64].try_into().unwrap(),
+            sender_current_balance: self._decode_ciphertext(proof_inputs[sender_at + 64..sender_at + 192].try_into().unwrap()),
+            // Addresses only takes 20 bytes, so we need to trim
+            token: Address::from_slice(&proof_inputs[sender_at + 204..sender_at + 224]),
+            domain: proof_inputs[sender_at + 224..sender_at + 256].try_into().unwrap(),
+            sender_balance_version: U256::from_be_slice(&proof_inputs[sender_at + 256..sender_at + 288]),
+            sender_new_balance: self._decode_ciphertext(proof_inputs[sender_at + 288..sender_at + 416].try_into().unwrap()),
+        }
+    }
+
+    /// Parse public inputs into ApproveConfidentialProofInputs struct.
+    fn _decode_approve_confidential_proof_inputs(
+        &self,
+        proof_inputs: [u8; APPROVE_INPUTS_LEN],
+    ) -> ApproveConfidentialProofInputs {
+        ApproveConfidentialProofInputs {
+            owner_pubkey: proof_inputs[0..64].try_into().unwrap(),
+            owner_current_balance: self._decode_ciphertext(proof_inputs[64..192].try_into().unwrap()),
+            // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
+            spender_address: Address::from_slice(&proof_inputs[204..224]),
+            spender_pubkey: proof_inputs[224..288].try_into().unwrap(),
+            token: Address::from_slice(&proof_inputs[300..320]),
+            domain: proof_inputs[320..352].try_into().unwrap(),
+            owner_balance_version: U256::from_be_slice(&proof_inputs[352..384]),
+            allowance_version: U256::from_be_slice(&proof_inputs[384..416]),
+            owner_current_allowance: self._decode_ciphertext(proof_inputs[416..544].try_into().unwrap()),
+            owner_new_balance: self._decode_ciphertext(proof_inputs[544..672].try_into().unwrap()),
+            owner_allowance: self._decode_ciphertext(proof_inputs[672..800].try_into().unwrap()),
+            spender_allowance: self._decode_ciphertext(proof_inputs[800..928].try_into().unwrap()),
+            allowance_open: !U256::from_be_slice(&proof_inputs[928..960]).is_zero(),
+        }
+    }
+
+    /// Parse public inputs into TransferFromConfidentialProofInputs struct.
+    fn _decode_transfer_from_confidential_proof_inputs(
+        &self,
+        proof_inputs: [u8; TRANSFER_FROM_INPUTS_LEN],
+    ) -> TransferFromConfidentialProofInputs {
+        TransferFromConfidentialProofInputs {
+            // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
+            owner_address: Address::from_slice(&proof_inputs[12..32]),
+            owner_pubkey: proof_inputs[32..96].try_into().unwrap(),
+            receiver_address: Address::from_slice(&proof_inputs[108..128]),
+            receiver_pubkey: proof_inputs[128..192].try_into().unwrap(),
+            spender_pubkey: proof_inputs[192..256].try_into().unwrap(),
+            owner_current_allowance: self._decode_ciphertext(proof_inputs[256..384].try_into().unwrap()),
+            spender_current_allowance: self._decode_ciphertext(proof_inputs[384..512].try_into().unwrap()),
+            token: Address::from_slice(&proof_inputs[524..544]),
+            domain: proof_inputs[544..576].try_into().unwrap(),
+            allowance_version: U256::from_be_slice(&proof_inputs[576..608]),
+            owner_new_allowance: self._decode_ciphertext(proof_inputs[608..736].try_into().unwrap()),
+            spender_new_allowance: self._decode_ciphertext(proof_inputs[736..864].try_into().unwrap()),
+            receiver_amount: self._decode_ciphertext(proof_inputs[864..992].try_into().unwrap()),
+            allowance_open: !U256::from_be_slice(&proof_inputs[992..1024]).is_zero(),
+        }
+    }
+
+    /// Parse public inputs into RekeyProofInputs struct.
+    fn _decode_rekey_proof_inputs(&self, proof_inputs: [u8; REKEY_INPUTS_LEN]) -> RekeyProofInputs {
+        // Per-slot sections follow the 192-byte header, one array after the other
+        let tokens_at = 192;
+        let versions_at = tokens_at + 32 * REKEY_SLOTS;
+        let old_at = versions_at + 32 * REKEY_SLOTS;
+        let new_at = old_at + 128 * REKEY_SLOTS;
+        let ciphertext_at = |start: usize, i: usize| {
+            self._decode_ciphertext(proof_inputs[start + 128 * i..start + 128 * (i + 1)].try_into().unwrap())
+        };
+
+        RekeyProofInputs {
+            old_pubkey: proof_inputs[0..64].try_into().unwrap(),
+            new_pubkey: proof_inputs[64..128].try_into().unwrap(),
+            // Addresses only takes 20 bytes, so we need to only take the last 20 bytes
+            user_address: Address::from_slice(&proof_inputs[140..160]),
+            domain: proof_inputs[160..192].try_into().unwrap(),
+            tokens: core::array::from_fn(|i| {
+                Address::from_slice(&proof_inputs[tokens_at + 32 * i + 12..tokens_at + 32 * (i + 1)])
+            }),
+            versions: core::array::from_fn(|i| {
+                U256::from_be_slice(&proof_inputs[versions_at + 32 * i..versions_at + 32 * (i + 1)])
+            }),
+            old_balances: core::array::from_fn(|i| ciphertext_at(old_at, i)),
+            new_balances: core::array::from_fn(|i| ciphertext_at(new_at, i)),
         }
     }
 
+    /// Domain separator bound into every proof: (chain_id << 160) | contract_address.
+    fn _domain_separator(&self) -> [u8; 32] {
+        let mut domain = [0u8; 32];
+        domain[4..12].copy_from_slice(&self.vm().chain_id().to_be_bytes());
+        domain[12..32].copy_from_slice(self.vm().contract_address().as_slice());
+        domain
+    }
+
+    fn _get_auditor_key(&self, token: Address) -> [u8; 64] {
+        let config = self.token_configs.get(token);
+        let mut out = [0u8; 64];
+        out[..32].copy_from_slice(config.auditor_x.get().as_slice());
+        out[32..].copy_from_slice(config.auditor_y.get().as_slice());
+        out
+    }
+
     fn _get_user_pk(&self, user: Address) -> [u8; 64] {
         let pk_x: FixedBytes<32> = self.pk_x.get(user);
         let pk_y: FixedBytes<32> = self.pk_y.get(user);
@@ -586,6 +3339,8 @@ impl ConfidentialERC20 {
 
     // Check if the current balance matches the proof inputs current amount
     fn _verify_current_amount(&self, token: Address, user: Address, proof_current_balance: &Ciphertext) -> bool {
+        // A balance must be under the user's current key to be spent or credited
+        if self._balance_epoch(token, user) != self.key_epochs.get(user) { return false; }
         let current_balance = self._decode_ciphertext(self.balance_of_enc(token, user));
         if current_balance.x1.x != proof_current_balance.x1.x { return false; }
         if current_balance.x1.y != proof_current_balance.x1.y { return false; }
@@ -594,126 +3349,698 @@ impl ConfidentialERC20 {
         true
     }
 
+    /// Spend the nullifier of a re-key proof, verify it and check it targets the caller
+    /// and `new_pk`.
+    fn _verify_rekey_proof(
+        &mut self,
+        verifier: Address,
+        proof_inputs: [u8; REKEY_INPUTS_LEN],
+        proof: AbiBytes,
+        caller_address: Address,
+        new_pk: [u8; 64],
+    ) -> Result<RekeyProofInputs, Error> {
+        self._spend_nullifier(verifier, &proof, &proof_inputs)?;
+        self._verify_proof(&proof_inputs, proof, verifier)?;
+
+        let rekey_proof_inputs = self._decode_rekey_proof_inputs(proof_inputs);
+        if rekey_proof_inputs.domain != self._domain_separator() {
+            return Err(Error::DomainMismatch(DomainMismatch { domain: rekey_proof_inputs.domain.into() }));
+        }
+        if rekey_proof_inputs.user_address != caller_address {
+            return Err(Error::UserAddressMismatch(UserAddressMismatch { user: rekey_proof_inputs.user_address }));
+        }
+        if rekey_proof_inputs.new_pubkey != new_pk {
+            return Err(Error::NewPkMismatch(NewPkMismatch { user: caller_address }));
+        }
+        Ok(rekey_proof_inputs)
+    }
+
+    /// Check every used slot of a re-key proof against the balance, or with `pending`
+    /// the pending bucket, it re-encrypts. Slot ciphertexts must be under the proof's
+    /// old key.
+    fn _sanity_checks_for_rekey(
+        &self,
+        caller_address: Address,
+        rekey_proof_inputs: &RekeyProofInputs,
+        pending: bool,
+    ) -> Result<(), Error> {
+        for i in 0..REKEY_SLOTS {
+            let token = rekey_proof_inputs.tokens[i];
+            if token == Address::ZERO {
+                continue;
+            }
+            let version = rekey_proof_inputs.versions[i];
+            // A slot repeated would credit the same pending bucket twice
+            let repeated = (0..i).any(|j| {
+                rekey_proof_inputs.tokens[j] == token && (!pending || rekey_proof_inputs.versions[j] == version)
+            });
+            if repeated {
+                return Err(Error::RekeyTokenMismatch(RekeyTokenMismatch { slot: U256::from(i), token }));
+            }
+
+            let (key_epoch, current) = if pending {
+                (version, self._pending_at(token, caller_address, version))
+            } else {
+                if self.balance_version(token, caller_address) != version {
+                    return Err(Error::StaleBalanceVersion(StaleBalanceVersion { token, user: caller_address, version }));
+                }
+                (self._balance_epoch(token, caller_address), self._get_balance(token, caller_address))
+            };
+            let key = self._user_pk_at(caller_address, key_epoch);
+            if key == [0u8; 64] || key != rekey_proof_inputs.old_pubkey {
+                return Err(Error::RetiredKeyMismatch(RetiredKeyMismatch { token, user: caller_address, key_epoch }));
+            }
+            if pending && current == Ciphertext::zero() {
+                return Err(Error::NoPendingBalance(NoPendingBalance { token, user: caller_address }));
+            }
+            if rekey_proof_inputs.old_balances[i] != current {
+                return Err(Error::StaleBalance(StaleBalance { token, user: caller_address }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Require a rotation proof to re-key the balance of every token `user` has
+    /// written one in, so no funds are left under the retired key.
+    fn _check_rekey_covers_balances(&self, user: Address, rekey_proof_inputs: &RekeyProofInputs) -> Result<(), Error> {
+        let held: Vec<Address> = self
+            .get_token_list()
+            .into_iter()
+            .filter(|&token| !self.balance_version(token, user).is_zero())
+            .collect();
+        if held.len() > REKEY_SLOTS {
+            return Err(Error::TooManyRekeyBalances(TooManyRekeyBalances { user, count: U256::from(held.len()) }));
+        }
+        if let Some(&token) = held.iter().find(|token| !rekey_proof_inputs.tokens.contains(token)) {
+            return Err(Error::RekeyIncomplete(RekeyIncomplete { user, token }));
+        }
+        Ok(())
+    }
+
+    /// Write the re-encrypted balances of a checked re-key proof, under the caller's
+    /// current key epoch.
+    fn _rekey_balances(&mut self, user: Address, rekey_proof_inputs: &RekeyProofInputs) {
+        let key_epoch = self.key_epochs.get(user);
+        for i in 0..REKEY_SLOTS {
+            let token = rekey_proof_inputs.tokens[i];
+            if token == Address::ZERO {
+                continue;
+            }
+            let new_balance = &rekey_proof_inputs.new_balances[i];
+            let balance_version = self._set_balance(token, user, new_balance);
+            let (t, u) = balance_key(token, user);
+            self.balance_epochs.setter(t).setter(u).set(key_epoch);
+            log(self.vm(), BalanceReencrypted {
+                token,
+                user,
+                balance_version,
+                new_balance: new_balance.to_bytes().into(),
+            });
+        }
+    }
+
+    fn _sanity_checks_for_approve(
+        &self,
+        owner: Address,
+        approve_proof_inputs: &ApproveConfidentialProofInputs,
+    ) -> Result<(), Error> {
+        let token = approve_proof_inputs.token;
+        let spender = approve_proof_inputs.spender_address;
+        if approve_proof_inputs.domain != self._domain_separator() {
+            return Err(Error::DomainMismatch(DomainMismatch { domain: approve_proof_inputs.domain.into() }));
+        }
+        if !self.is_supported_token(token) {
+            return Err(Error::TokenNotSupported(TokenNotSupported { token }));
+        }
+        self._when_token_not_paused(token)?;
+        self._check_compliance(owner, spender, token)?;
+        // Allowances on audited tokens could never be spent, since the transfer-from
+        // circuit carries no auditor ciphertext; revoking stays open to refund them
+        if self._get_auditor_key(token) != [0u8; 64] && approve_proof_inputs.allowance_open {
+            return Err(Error::AuditedToken(AuditedToken { token }));
+        }
+
+        // Spender checks
+        let spender_pk = self._get_user_pk(spender);
+        if spender_pk == [0u8; 64] {
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: spender }));
+        }
+        if spender_pk != approve_proof_inputs.spender_pubkey {
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: spender }));
+        }
+
+        // Owner checks
+        if self._get_user_pk(owner) != approve_proof_inputs.owner_pubkey {
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: owner }));
+        }
+        if !self._verify_current_amount(token, owner, &approve_proof_inputs.owner_current_balance) {
+            return Err(Error::StaleBalance(StaleBalance { token, user: owner }));
+        }
+        if self.balance_version(token, owner) != approve_proof_inputs.owner_balance_version {
+            return Err(Error::StaleBalanceVersion(StaleBalanceVersion {
+                token,
+                user: owner,
+                version: approve_proof_inputs.owner_balance_version,
+            }));
+        }
+
+        // Previous allowance, refunded into the new balance
+        let (owner_allowance, _) = self._get_allowance(token, owner, spender);
+        if owner_allowance != approve_proof_inputs.owner_current_allowance {
+            return Err(Error::StaleAllowance(StaleAllowance { token, owner, spender }));
+        }
+        if self.allowance_version(token, owner, spender) != approve_proof_inputs.allowance_version {
+            return Err(Error::StaleAllowanceVersion(StaleAllowanceVersion {
+                token,
+                owner,
+                spender,
+                version: approve_proof_inputs.allowance_version,
+            }));
+        }
+        Ok(())
+    }
+
+    fn _sanity_checks_for_transfer_from(
+        &self,
+        owner: Address,
+        spender: Address,
+        transfer_from_proof_inputs: &TransferFromConfidentialProofInputs,
+    ) -> Result<(), Error> {
+        let token = transfer_from_proof_inputs.token;
+        let receiver = transfer_from_proof_inputs.receiver_address;
+        if transfer_from_proof_inputs.domain != self._domain_separator() {
+            return Err(Error::DomainMismatch(DomainMismatch { domain: transfer_from_proof_inputs.domain.into() }));
+        }
+        if !self.is_supported_token(token) {
+            return Err(Error::TokenNotSupported(TokenNotSupported { token }));
+        }
+        self._when_token_not_paused(token)?;
+        // The transfer-from circuit carries no auditor ciphertext
+        if self._get_auditor_key(token) != [0u8; 64] {
+            return Err(Error::AuditedToken(AuditedToken { token }));
+        }
+        if self.frozen.get(spender) {
+            return Err(Error::FrozenAccount(FrozenAccount { account: spender }));
+        }
+        self._check_compliance(owner, receiver, token)?;
+
+        if transfer_from_proof_inputs.owner_address != owner {
+            return Err(Error::UserAddressMismatch(UserAddressMismatch { user: transfer_from_proof_inputs.owner_address }));
+        }
+        if self._get_user_pk(owner) != transfer_from_proof_inputs.owner_pubkey {
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: owner }));
+        }
+        if self._get_user_pk(spender) != transfer_from_proof_inputs.spender_pubkey {
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: spender }));
+        }
+
+        // Receiver checks
+        let receiver_pk = self._get_user_pk(receiver);
+        if receiver_pk == [0u8; 64] {
+            return Err(Error::ReceiverNotRegistered(ReceiverNotRegistered { receiver }));
+        }
+        if receiver_pk != transfer_from_proof_inputs.receiver_pubkey {
+            return Err(Error::ReceiverPkMismatch(ReceiverPkMismatch { receiver }));
+        }
+
+        // Allowance checks
+        let allowance_version = self.allowance_version(token, owner, spender);
+        if allowance_version.is_zero() {
+            return Err(Error::NoAllowance(NoAllowance { token, owner, spender }));
+        }
+        let (owner_allowance, spender_allowance) = self._get_allowance(token, owner, spender);
+        if owner_allowance != transfer_from_proof_inputs.owner_current_allowance
+            || spender_allowance != transfer_from_proof_inputs.spender_current_allowance
+        {
+            return Err(Error::StaleAllowance(StaleAllowance { token, owner, spender }));
+        }
+        if allowance_version != transfer_from_proof_inputs.allowance_version {
+            return Err(Error::StaleAllowanceVersion(StaleAllowanceVersion {
+                token,
+                owner,
+                spender,
+                version: transfer_from_proof_inputs.allowance_version,
+            }));
+        }
+        Ok(())
+    }
+
     fn _sanity_checks_for_transfer(
         &self,
         caller_address: Address,
         transfer_proof_inputs: &TransferConfidentialProofInputs
-    ) -> Result<(), Vec<u8>> {
-        if !self.supported_tokens.get(transfer_proof_inputs.token) {
-            return Err("Token not supported".into());
+    ) -> Result<(), Error> {
+        if transfer_proof_inputs.domain != self._domain_separator() {
+            return Err(Error::DomainMismatch(DomainMismatch { domain: transfer_proof_inputs.domain.into() }));
+        }
+        if !self.is_supported_token(transfer_proof_inputs.token) {
+            return Err(Error::TokenNotSupported(TokenNotSupported { token: transfer_proof_inputs.token }));
         }
+        self._when_token_not_paused(transfer_proof_inputs.token)?;
+        if transfer_proof_inputs.auditor_pubkey != self._get_auditor_key(transfer_proof_inputs.token) {
+            return Err(Error::AuditorKeyMismatch(AuditorKeyMismatch { token: transfer_proof_inputs.token }));
+        }
+        self._check_compliance(caller_address, transfer_proof_inputs.receiver_address, transfer_proof_inputs.token)?;
+
         // Receiver checks
         let receiver_registered_pubkey = self._get_user_pk(transfer_proof_inputs.receiver_address);
         if receiver_registered_pubkey == [0u8; 64] {
-            return Err("Receiver not registered".into());
+            return Err(Error::ReceiverNotRegistered(ReceiverNotRegistered { receiver: transfer_proof_inputs.receiver_address }));
         }
         if receiver_registered_pubkey != transfer_proof_inputs.receiver_pubkey {
-            return Err("Receiver public key mismatch".into());
+            return Err(Error::ReceiverPkMismatch(ReceiverPkMismatch { receiver: transfer_proof_inputs.receiver_address }));
         }
 
-        if !self._verify_current_amount(
-            transfer_proof_inputs.token, 
-            transfer_proof_inputs.receiver_address,
-            &transfer_proof_inputs.receiver_current_balance
-        ) {
-            return Err("Receiver Current balance mismatch".into());
-        }
 
         // Sender checks
         let registered_sender_pk = self._get_user_pk(caller_address);
         if registered_sender_pk != transfer_proof_inputs.sender_pubkey {
-            return Err("Sender public key mismatch".into());
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: caller_address }));
         }
         if !self._verify_current_amount(
             transfer_proof_inputs.token,
             caller_address, 
             &transfer_proof_inputs.sender_current_balance
         ) {
-            return Err("Sender Current balance mismatch".into());
+            return Err(Error::StaleBalance(StaleBalance { token: transfer_proof_inputs.token, user: caller_address }));
+        }
+        if self.balance_version(transfer_proof_inputs.token, caller_address)
+            != transfer_proof_inputs.sender_balance_version
+        {
+            return Err(Error::StaleBalanceVersion(StaleBalanceVersion {
+                token: transfer_proof_inputs.token,
+                user: caller_address,
+                version: transfer_proof_inputs.sender_balance_version,
+            }));
+        }
+        Ok(())
+    }
+
+    fn _sanity_checks_for_batch_transfer(
+        &self,
+        caller_address: Address,
+        batch_proof_inputs: &BatchTransferConfidentialProofInputs,
+    ) -> Result<(), Error> {
+        let token = batch_proof_inputs.token;
+        if batch_proof_inputs.domain != self._domain_separator() {
+            return Err(Error::DomainMismatch(DomainMismatch { domain: batch_proof_inputs.domain.into() }));
+        }
+        if !self.is_supported_token(token) {
+            return Err(Error::TokenNotSupported(TokenNotSupported { token }));
+        }
+        self._when_token_not_paused(token)?;
+        // The batch circuit carries no auditor ciphertext
+        if self._get_auditor_key(token) != [0u8; 64] {
+            return Err(Error::AuditedToken(AuditedToken { token }));
+        }
+
+        // Receiver checks
+        if batch_proof_inputs.receivers.is_empty() {
+            return Err(Error::EmptyBatch(EmptyBatch {}));
+        }
+        for receiver in batch_proof_inputs.receivers.iter() {
+            let receiver_registered_pubkey = self._get_user_pk(receiver.receiver_address);
+            if receiver_registered_pubkey == [0u8; 64] {
+                return Err(Error::ReceiverNotRegistered(ReceiverNotRegistered { receiver: receiver.receiver_address }));
+            }
+            if receiver_registered_pubkey != receiver.receiver_pubkey {
+                return Err(Error::ReceiverPkMismatch(ReceiverPkMismatch { receiver: receiver.receiver_address }));
+            }
+            self._check_compliance(caller_address, receiver.receiver_address, token)?;
+        }
+
+        // Sender checks
+        if self._get_user_pk(caller_address) != batch_proof_inputs.sender_pubkey {
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: caller_address }));
+        }
+        if !self._verify_current_amount(token, caller_address, &batch_proof_inputs.sender_current_balance) {
+            return Err(Error::StaleBalance(StaleBalance { token, user: caller_address }));
+        }
+        if self.balance_version(token, caller_address) != batch_proof_inputs.sender_balance_version {
+            return Err(Error::StaleBalanceVersion(StaleBalanceVersion {
+                token,
+                user: caller_address,
+                version: batch_proof_inputs.sender_balance_version,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Check a relayed intent's relayer, deadline and signature, then consume the
+    /// account's nonce. `struct_hash` must already include the current nonce.
+    fn _use_intent(
+        &mut self,
+        account: Address,
+        struct_hash: FixedBytes<32>,
+        relayer: Address,
+        deadline: U256,
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        if relayer != Address::ZERO && relayer != self.vm().msg_sender() {
+            return Err(Error::RelayerMismatch(RelayerMismatch { relayer }));
+        }
+        if U256::from(self.vm().block_timestamp()) > deadline {
+            return Err(Error::IntentExpired(IntentExpired { deadline }));
+        }
+
+        let digest = keccak256(
+            [&[0x19, 0x01], self.eip712_domain_separator().as_slice(), struct_hash.as_slice()].concat(),
+        );
+        let signer = self._recover_signer(digest, signature);
+        if signer == Address::ZERO || signer != account {
+            return Err(Error::InvalidSignature(InvalidSignature {}));
+        }
+
+        let nonce = self.nonces.get(account);
+        self.nonces.setter(account).set(nonce + U256::from(1));
+        log(self.vm(), IntentRelayed { account, relayer: self.vm().msg_sender(), nonce });
+        Ok(())
+    }
+
+    /// Recover the signer of a 65-byte `r || s || v` signature with the ecrecover
+    /// precompile. Returns the zero address for malformed or malleable signatures.
+    fn _recover_signer(&self, digest: FixedBytes<32>, signature: &[u8]) -> Address {
+        if signature.len() != 65 {
+            return Address::ZERO;
+        }
+        let s = U256::from_be_slice(&signature[32..64]);
+        let v = match signature[64] {
+            0 | 1 => signature[64] + 27,
+            v => v,
+        };
+        if s > SECP256K1_HALF_ORDER || (v != 27 && v != 28) {
+            return Address::ZERO;
+        }
+
+        let mut input = [0u8; 128];
+        input[0..32].copy_from_slice(digest.as_slice());
+        input[63] = v;
+        input[64..128].copy_from_slice(&signature[0..64]);
+        match self.vm().static_call(&Call::new(), ECRECOVER, &input) {
+            Ok(data) if data.len() == 32 => Address::from_slice(&data[12..32]),
+            _ => Address::ZERO,
+        }
+    }
+
+    /// Settle the optional confidential relayer fee: a transfer proof from `account`
+    /// to the relayer (the caller). Empty inputs mean no fee.
+    fn _pay_relayer_fee(
+        &mut self,
+        account: Address,
+        fee_circuit_version: u32,
+        fee_proof_inputs: Vec<u8>,
+        fee_proof: AbiBytes,
+    ) -> Result<(), Error> {
+        if fee_proof_inputs.is_empty() {
+            return Ok(());
+        }
+        // The fee is a full confidential transfer
+        self._when_not_paused(OP_TRANSFER)?;
+        let fee_proof_inputs_fixed: [u8; TRANSFER_INPUTS_LEN] = fixed_proof_inputs(fee_proof_inputs)?;
+        let receiver = self._decode_transfer_confidential_proof_inputs(fee_proof_inputs_fixed).receiver_address;
+        if receiver != self.vm().msg_sender() {
+            return Err(Error::FeeReceiverMismatch(FeeReceiverMismatch { receiver }));
+        }
+        self._transfer_confidential(account, fee_circuit_version, fee_proof_inputs_fixed, fee_proof)
+    }
+
+    /// Transfer flow shared by `transfer_confidential` and the relayed entry points.
+    /// Pause gates are left to the caller.
+    fn _transfer_confidential(
+        &mut self,
+        from: Address,
+        circuit_version: u32,
+        proof_inputs_fixed: [u8; TRANSFER_INPUTS_LEN],
+        proof: AbiBytes,
+    ) -> Result<(), Error> {
+        let verifier = self._verifier(CIRCUIT_TRANSFER, circuit_version)?;
+        self._non_reentrant()?;
+
+        let sender_pubkey = self._get_user_pk(from);
+        if sender_pubkey == [0u8; 64] {
+            self._release_reentrancy();
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: from }));
+        }
+
+        if let Err(e) = self._spend_nullifier(verifier, &proof, &proof_inputs_fixed) {
+            self._release_reentrancy();
+            return Err(e);
+        }
+
+        if let Err(e) = self._verify_proof(&proof_inputs_fixed, proof, verifier) {
+            self._release_reentrancy();
+            return Err(e.into());
+        }
+
+        let transfer_proof_inputs = self._decode_transfer_confidential_proof_inputs(proof_inputs_fixed);
+
+        let result = self._sanity_checks_for_transfer(from, &transfer_proof_inputs);
+        if let Err(err) = result {
+            self._release_reentrancy();
+            return Err(err);
         }
+
+        let token = transfer_proof_inputs.token;
+        let receiver_address = transfer_proof_inputs.receiver_address;
+        let sender_new_balance = transfer_proof_inputs.sender_new_balance;
+
+        let sender_balance_version = self._set_balance(token, from, &sender_new_balance);
+        let receiver_new_pending = self._add_pending(token, receiver_address, &transfer_proof_inputs.receiver_amount);
+
+        // Emit event with new ciphertexts for indexing/off-chain
+        log(self.vm(), TransferConfidential {
+            token,
+            from,
+            to: transfer_proof_inputs.receiver_address,
+            sender_balance_version,
+            sender_new_balance: sender_new_balance.to_bytes().into(),
+            receiver_amount: transfer_proof_inputs.receiver_amount.to_bytes().into(),
+            receiver_new_pending: receiver_new_pending.to_bytes().into(),
+            auditor_amount: transfer_proof_inputs.auditor_amount.to_bytes().into(),
+        });
+
+        self._release_reentrancy();
         Ok(())
     }
 
+    /// Shared deposit/withdraw flow. `account` owns the encrypted balance and must own
+    /// the proof key; `counterparty` is the payer (deposit) or recipient (withdraw).
+    /// `leg` selects how plain tokens (or ETH) move in or out of custody.
+    #[allow(clippy::too_many_arguments)]
     fn _deposit_widthdraw(
         &mut self, 
-        proof_inputs: [u8; 416], 
+        circuit_version: u32,
+        proof_inputs: [u8; DEPOSIT_WITHDRAW_INPUTS_LEN], 
         proof: AbiBytes,
         is_deposit: bool,
-    ) -> Result<(), Vec<u8>> {
+        leg: PlainLeg,
+        account: Address,
+        counterparty: Address,
+    ) -> Result<(), Error> {
+        self._when_not_paused(if is_deposit { OP_DEPOSIT } else { OP_WITHDRAW })?;
+        let circuit = if is_deposit { CIRCUIT_DEPOSIT } else { CIRCUIT_WITHDRAW };
+        let verifier = self._verifier(circuit, circuit_version)?;
         self._non_reentrant()?;
 
-        let from = self.vm().msg_sender();
-        let user_pk = self._get_user_pk(from);
+        let user_pk = self._get_user_pk(account);
         if user_pk == [0u8; 64] {
             self._release_reentrancy();
-            return Err("User not registered".into());
+            return Err(Error::UserNotRegistered(UserNotRegistered { user: account }));
         }
 
-        let verifier = if is_deposit {self.deposit_verifier.get()} else { self.withdraw_verifier.get()};
+        if let Err(e) = self._spend_nullifier(verifier, &proof, &proof_inputs) {
+            self._release_reentrancy();
+            return Err(e);
+        }
     
         if let Err(e) = self._verify_proof(&proof_inputs, proof, verifier) {
             self._release_reentrancy();
-            return Err(["Proof verification failed: ".as_bytes(), &e].concat());
+            return Err(e.into());
+        }
+
+        let deposit_proof_inputs = self._decode_deposit_withdraw_proof_inputs(proof_inputs);
+
+        if deposit_proof_inputs.domain != self._domain_separator() {
+            self._release_reentrancy();
+            return Err(Error::DomainMismatch(DomainMismatch { domain: deposit_proof_inputs.domain.into() }));
+        }
+
+        if !self.is_supported_token(deposit_proof_inputs.token) {
+            self._release_reentrancy();
+            return Err(Error::TokenNotSupported(TokenNotSupported { token: deposit_proof_inputs.token }));
+        }
+
+        if matches!(leg, PlainLeg::Native) && deposit_proof_inputs.token != Address::from_str(WETH_TOKEN_ADDRESS).unwrap() {
+            self._release_reentrancy();
+            return Err(Error::NotWrappedNative(NotWrappedNative { token: deposit_proof_inputs.token }));
+        }
+
+        if let Err(e) = self._when_token_not_paused(deposit_proof_inputs.token) {
+            self._release_reentrancy();
+            return Err(e);
         }
 
-        let deposit_proof_inputs = self
-            ._decode_deposit_withdraw_proof_inputs(proof_inputs)
-            .map_err(|_| "Failed to decode deposit/withdraw proof inputs".as_bytes().to_vec())?;
+        if deposit_proof_inputs.auditor_pubkey != self._get_auditor_key(deposit_proof_inputs.token) {
+            self._release_reentrancy();
+            return Err(Error::AuditorKeyMismatch(AuditorKeyMismatch { token: deposit_proof_inputs.token }));
+        }
+
+        if deposit_proof_inputs.counterparty != counterparty {
+            self._release_reentrancy();
+            return Err(if is_deposit {
+                Error::PayerMismatch(PayerMismatch { payer: deposit_proof_inputs.counterparty })
+            } else {
+                Error::RecipientMismatch(RecipientMismatch { recipient: deposit_proof_inputs.counterparty })
+            });
+        }
 
-        if !self.supported_tokens.get(deposit_proof_inputs.token) {
+        let (from, to) = if is_deposit { (counterparty, account) } else { (account, counterparty) };
+        if let Err(e) = self._check_compliance(from, to, deposit_proof_inputs.token) {
             self._release_reentrancy();
-            return Err("Token not supported".into());
+            return Err(e);
         }
 
         if user_pk != deposit_proof_inputs.user_pubkey {
             self._release_reentrancy();
-            return Err("User public key mismatch".into());
+            return Err(Error::UserPkMismatch(UserPkMismatch { user: account }));
+        }
+
+        if !self._verify_current_amount(deposit_proof_inputs.token, account, &deposit_proof_inputs.current_balance) {
+            self._release_reentrancy();
+            return Err(Error::StaleBalance(StaleBalance { token: deposit_proof_inputs.token, user: account }));
         }
 
-        if !self._verify_current_amount(deposit_proof_inputs.token, from, &deposit_proof_inputs.current_balance) {
+        if self.balance_version(deposit_proof_inputs.token, account) != deposit_proof_inputs.balance_version {
             self._release_reentrancy();
-            return Err("Current balance mismatch".into());
+            return Err(Error::StaleBalanceVersion(StaleBalanceVersion { token: deposit_proof_inputs.token, user: account, version: deposit_proof_inputs.balance_version }));
         }
 
         let token = deposit_proof_inputs.token;
-        let user_address = deposit_proof_inputs.user_address;
         
         let raw_amount = deposit_proof_inputs.amount;
-        // Since ELGAMAL requires amounts not bigger than 40 bits, we need to scale the amount by 10^6
-        let scale_factor = U256::from(1000000);
+        // Since ELGAMAL requires amounts not bigger than 40 bits, we need to scale the amount
+        let scale_factor = self.token_configs.get(token).scale_factor.get();
         let amount = raw_amount * scale_factor;
         let new_balance = deposit_proof_inputs.new_balance;
+        let auditor_amount = deposit_proof_inputs.auditor_amount;
+        // The encrypted balance moves by exactly `amount`; the fee is charged on top of
+        // a deposit and taken out of a withdrawal
+        let fee = self.quote_fee(token, amount);
+
+        if is_deposit && self.token_configs.get(token).fee_on_transfer.get() {
+            self._release_reentrancy();
+            return Err(Error::FeeOnTransferToken(FeeOnTransferToken { token }));
+        }
+        let custody_before = match self._custody_balance(token) {
+            Ok(balance) => balance,
+            Err(e) => {
+                self._release_reentrancy();
+                return Err(e);
+            }
+        };
 
         // Move plain tokens into custody
         if is_deposit {
-            self._transfer_from(
-                token,
-                from,
-                self.vm().contract_address(),
-                amount
-            )?;
+            match leg {
+                PlainLeg::Native => {
+                    let value = self.vm().msg_value();
+                    if value != amount + fee {
+                        self._release_reentrancy();
+                        return Err(Error::EthValueMismatch(EthValueMismatch { expected: amount + fee, value }));
+                    }
+                    if let Err(e) = self._wrap_eth(token, value) {
+                        self._release_reentrancy();
+                        return Err(e);
+                    }
+                }
+                PlainLeg::Permit2 { nonce, deadline, signature } => {
+                    let pulled = self._permit2_transfer_from(token, counterparty, amount + fee, nonce, deadline, signature);
+                    if let Err(e) = pulled {
+                        self._release_reentrancy();
+                        return Err(e);
+                    }
+                }
+                PlainLeg::Permit { deadline, v, r, s } => {
+                    self._permit(token, counterparty, amount + fee, deadline, v, r, s);
+                    let pulled = self._transfer_from(token, counterparty, self.vm().contract_address(), amount + fee);
+                    if let Err(e) = pulled {
+                        self._release_reentrancy();
+                        return Err(e);
+                    }
+                }
+                PlainLeg::Erc20 => {
+                    let pulled = self._transfer_from(
+                        token,
+                        counterparty,
+                        self.vm().contract_address(),
+                        amount + fee
+                    );
+                    if let Err(e) = pulled {
+                        self._release_reentrancy();
+                        return Err(e);
+                    }
+                }
+            }
+            if let Err(e) = self._check_custody_delta(token, custody_before, amount + fee, true) {
+                self._release_reentrancy();
+                return Err(e);
+            }
+            let total_deposited = self.total_deposited.get(token) + amount;
+            self.total_deposited.setter(token).set(total_deposited);
+            self._accrue_fee(token, account, fee);
 
             // Store the NEW balance ciphertext (no math on-chain)
-            self._set_balance(token, user_address, &new_balance);
+            let balance_version = self._set_balance(token, account, &new_balance);
 
-            evm::log(Deposit {
+            log(self.vm(), Deposit {
                 token,
-                user_address
+                user_address: account,
+                payer: counterparty,
+                amount,
+                balance_version,
+                new_balance: new_balance.to_bytes().into(),
+                auditor_amount: auditor_amount.to_bytes().into(),
             });
         } else {
             // withdraw
-            self._transfer(
-                token,
-                user_address,
-                amount
-            )?;
-
-            // Store the NEW balance ciphertext (no math on-chain)
-            self._set_balance(token, user_address, &new_balance);
-
-            evm::log(Withdraw {
+            if fee > amount {
+                self._release_reentrancy();
+                return Err(Error::FeeExceedsAmount(FeeExceedsAmount { token, amount, fee }));
+            }
+            self._accrue_fee(token, account, fee);
+            let total_withdrawn = self.total_withdrawn.get(token) + amount;
+            self.total_withdrawn.setter(token).set(total_withdrawn);
+
+            // Store the NEW balance ciphertext (no math on-chain) before paying out, so a
+            // recipient re-entering through the payout only ever sees the debited balance
+            let balance_version = self._set_balance(token, account, &new_balance);
+
+            let paid = if matches!(leg, PlainLeg::Native) {
+                self._unwrap_eth(token, counterparty, amount - fee)
+            } else {
+                self._transfer(
+                    token,
+                    counterparty,
+                    amount - fee
+                )
+            };
+            if let Err(e) = paid {
+                self._release_reentrancy();
+                return Err(e);
+            }
+            if let Err(e) = self._check_custody_delta(token, custody_before, amount - fee, false) {
+                self._release_reentrancy();
+                return Err(e);
+            }
+
+            log(self.vm(), Withdraw {
                 token,
-                user_address
+                user_address: account,
+                recipient: counterparty,
+                amount,
+                balance_version,
+                new_balance: new_balance.to_bytes().into(),
+                auditor_amount: auditor_amount.to_bytes().into(),
             });
         }
 
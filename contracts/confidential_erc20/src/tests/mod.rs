@@ -1,8 +1,17 @@
-#![cfg(test)]
-
 use super::*;
 use stylus_sdk::testing::*;
-use stylus_sdk::alloy_primitives::{Address, address, U256, FixedBytes, keccak256};
+use stylus_sdk::alloy_primitives::{Address, U256};
+use stylus_sdk::alloy_sol_types::{SolError, SolEvent};
+
+// Last emitted event of type `E`
+fn last_event<E: SolEvent>(vm: &TestVM) -> E {
+    vm.get_emitted_logs()
+        .iter()
+        .rev()
+        .find(|(topics, _)| topics.first() == Some(&E::SIGNATURE_HASH))
+        .map(|(topics, data)| E::decode_raw_log(topics.iter().copied(), data, true).unwrap())
+        .expect("event not emitted")
+}
 
 // helper
 fn addr(n: u8) -> Address {
@@ -11,124 +20,1006 @@ fn addr(n: u8) -> Address {
     Address::from(a)
 }
 
+fn weth() -> Address {
+    Address::from_str(WETH_TOKEN_ADDRESS).unwrap()
+}
+
+// ABI-encoded bool returned by a verifier
+fn abi_bool(value: bool) -> Vec<u8> {
+    let mut out = vec![0u8; 32];
+    out[31] = value as u8;
+    out
+}
+
+fn verify_calldata(proof_inputs: &[u8], proof: &[u8]) -> Vec<u8> {
+    verifyCall {
+        proof: Bytes::from(proof.to_vec()),
+        publicInputs: proof_inputs
+            .chunks(32)
+            .map(|chunk| FixedBytes::<32>::from_slice(chunk))
+            .collect(),
+    }
+    .abi_encode()
+}
+
+// Encrypted zero under `pk`, laid out as returned by `balance_of_enc`
+fn zero_balance(pk: [u8; 64]) -> [u8; 128] {
+    let mut out = [0u8; 128];
+    out[0..32].copy_from_slice(&G_GENERATOR_X);
+    out[32..64].copy_from_slice(&G_GENERATOR_Y);
+    out[64..128].copy_from_slice(&pk);
+    out
+}
+
+// (chain_id << 160) | contract_address for the test VM
+fn domain(vm: &TestVM) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out[4..12].copy_from_slice(&vm.chain_id().to_be_bytes());
+    out[12..32].copy_from_slice(vm.contract_address().as_slice());
+    out
+}
+
+// Fixed `r || s || v` signature, recovered to `signer` through a mocked ecrecover
+fn mock_signature(vm: &TestVM, digest: FixedBytes<32>, signer: Address) -> Vec<u8> {
+    let mut signature = vec![0x11; 65];
+    signature[32..64].fill(0x22);
+    signature[64] = 27;
+
+    let mut input = [0u8; 128];
+    input[0..32].copy_from_slice(digest.as_slice());
+    input[63] = 27;
+    input[64..128].copy_from_slice(&signature[0..64]);
+    let mut output = vec![0u8; 32];
+    output[12..32].copy_from_slice(signer.as_slice());
+    vm.mock_static_call(Address::with_last_byte(1), input.to_vec(), Ok(output));
+    signature
+}
+
+// EIP-712 digest of a TransferIntent, encoded independently of the contract
+fn transfer_intent_digest(
+    contract: &ConfidentialERC20,
+    account: Address,
+    (circuit_version, proof_inputs): (u32, &[u8]),
+    (fee_circuit_version, fee_proof_inputs): (u32, &[u8]),
+    relayer: Address,
+    nonce: u64,
+    deadline: u64,
+) -> FixedBytes<32> {
+    let mut encoded = keccak256(TRANSFER_INTENT_TYPE).to_vec();
+    encoded.extend_from_slice(&[0u8; 12]);
+    encoded.extend_from_slice(account.as_slice());
+    encoded.extend_from_slice(&U256::from(circuit_version).to_be_bytes::<32>());
+    encoded.extend_from_slice(keccak256(proof_inputs).as_slice());
+    encoded.extend_from_slice(&U256::from(fee_circuit_version).to_be_bytes::<32>());
+    encoded.extend_from_slice(keccak256(fee_proof_inputs).as_slice());
+    encoded.extend_from_slice(&[0u8; 12]);
+    encoded.extend_from_slice(relayer.as_slice());
+    encoded.extend_from_slice(&U256::from(nonce).to_be_bytes::<32>());
+    encoded.extend_from_slice(&U256::from(deadline).to_be_bytes::<32>());
+    let struct_hash = keccak256(encoded);
+    keccak256([&[0x19, 0x01], contract.eip712_domain_separator().as_slice(), struct_hash.as_slice()].concat())
+}
+
+// EIP-712 digest of a WithdrawIntent at the account's current nonce
+fn withdraw_intent_digest(
+    contract: &ConfidentialERC20,
+    account: Address,
+    recipient: Address,
+    (circuit_version, proof_inputs): (u32, &[u8]),
+    (fee_circuit_version, fee_proof_inputs): (u32, &[u8]),
+    relayer: Address,
+    deadline: u64,
+) -> FixedBytes<32> {
+    let mut encoded = keccak256(WITHDRAW_INTENT_TYPE).to_vec();
+    for address in [account, recipient] {
+        encoded.extend_from_slice(&[0u8; 12]);
+        encoded.extend_from_slice(address.as_slice());
+    }
+    encoded.extend_from_slice(&U256::from(circuit_version).to_be_bytes::<32>());
+    encoded.extend_from_slice(keccak256(proof_inputs).as_slice());
+    encoded.extend_from_slice(&U256::from(fee_circuit_version).to_be_bytes::<32>());
+    encoded.extend_from_slice(keccak256(fee_proof_inputs).as_slice());
+    encoded.extend_from_slice(&[0u8; 12]);
+    encoded.extend_from_slice(relayer.as_slice());
+    encoded.extend_from_slice(&contract.nonces(account).to_be_bytes::<32>());
+    encoded.extend_from_slice(&U256::from(deadline).to_be_bytes::<32>());
+    let struct_hash = keccak256(encoded);
+    keccak256([&[0x19, 0x01], contract.eip712_domain_separator().as_slice(), struct_hash.as_slice()].concat())
+}
+
+// Deposit/withdraw public inputs, defaulting to a valid first deposit of 10 WETH
+struct DepositWithdrawInputs {
+    pk: [u8; 64],
+    current: [u8; 128],
+    counterparty: Address,
+    token: Address,
+    amount: u64,
+    domain: [u8; 32],
+    version: u64,
+    auditor_pk: [u8; 64],
+    new_balance: [u8; 128],
+    auditor_amount: [u8; 128],
+}
+
+impl DepositWithdrawInputs {
+    fn new(vm: &TestVM, pk: [u8; 64], counterparty: Address) -> Self {
+        Self {
+            pk,
+            current: zero_balance(pk),
+            counterparty,
+            token: weth(),
+            amount: 10,
+            domain: domain(vm),
+            version: 0,
+            auditor_pk: [0u8; 64],
+            new_balance: [2u8; 128],
+            auditor_amount: [0u8; 128],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; DEPOSIT_WITHDRAW_INPUTS_LEN];
+        out[0..64].copy_from_slice(&self.pk);
+        out[64..192].copy_from_slice(&self.current);
+        out[204..224].copy_from_slice(self.counterparty.as_slice());
+        out[236..256].copy_from_slice(self.token.as_slice());
+        out[256..288].copy_from_slice(&U256::from(self.amount).to_be_bytes::<32>());
+        out[288..320].copy_from_slice(&self.domain);
+        out[320..352].copy_from_slice(&U256::from(self.version).to_be_bytes::<32>());
+        out[352..416].copy_from_slice(&self.auditor_pk);
+        out[416..544].copy_from_slice(&self.new_balance);
+        out[544..672].copy_from_slice(&self.auditor_amount);
+        out
+    }
+}
+
+// Confidential transfer public inputs, defaulting to a valid first transfer from `sender`
+struct TransferInputs {
+    receiver: Address,
+    receiver_pk: [u8; 64],
+    sender_pk: [u8; 64],
+    sender_current: [u8; 128],
+    token: Address,
+    domain: [u8; 32],
+    sender_version: u64,
+    auditor_pk: [u8; 64],
+    sender_new_balance: [u8; 128],
+    receiver_amount: [u8; 128],
+    auditor_amount: [u8; 128],
+}
+
+impl TransferInputs {
+    fn new(vm: &TestVM, sender_pk: [u8; 64], receiver: Address, receiver_pk: [u8; 64]) -> Self {
+        Self {
+            receiver,
+            receiver_pk,
+            sender_pk,
+            sender_current: zero_balance(sender_pk),
+            token: weth(),
+            domain: domain(vm),
+            sender_version: 0,
+            auditor_pk: [0u8; 64],
+            sender_new_balance: [3u8; 128],
+            receiver_amount: ciphertext(g(1), g(1)),
+            auditor_amount: [0u8; 128],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; TRANSFER_INPUTS_LEN];
+        out[12..32].copy_from_slice(self.receiver.as_slice());
+        out[32..96].copy_from_slice(&self.receiver_pk);
+        out[96..160].copy_from_slice(&self.sender_pk);
+        out[160..288].copy_from_slice(&self.sender_current);
+        out[300..320].copy_from_slice(self.token.as_slice());
+        out[320..352].copy_from_slice(&self.domain);
+        out[352..384].copy_from_slice(&U256::from(self.sender_version).to_be_bytes::<32>());
+        out[384..448].copy_from_slice(&self.auditor_pk);
+        out[448..576].copy_from_slice(&self.sender_new_balance);
+        out[576..704].copy_from_slice(&self.receiver_amount);
+        out[704..832].copy_from_slice(&self.auditor_amount);
+        out
+    }
+}
+
+// Batch transfer public inputs; empty slots keep the zero receiver address
+struct BatchTransferInputs {
+    receivers: Vec<(Address, [u8; 64], [u8; 128])>,
+    sender_pk: [u8; 64],
+    sender_current: [u8; 128],
+    token: Address,
+    domain: [u8; 32],
+    sender_version: u64,
+    sender_new_balance: [u8; 128],
+}
+
+impl BatchTransferInputs {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; BATCH_TRANSFER_INPUTS_LEN];
+        let sender_at = 96 * MAX_BATCH_RECEIVERS;
+        let amounts_at = sender_at + 416;
+        for (i, (receiver, receiver_pk, amount)) in self.receivers.iter().enumerate() {
+            out[96 * i + 12..96 * i + 32].copy_from_slice(receiver.as_slice());
+            out[96 * i + 32..96 * i + 96].copy_from_slice(receiver_pk);
+            out[amounts_at + 128 * i..amounts_at + 128 * (i + 1)].copy_from_slice(amount);
+        }
+        out[sender_at..sender_at + 64].copy_from_slice(&self.sender_pk);
+        out[sender_at + 64..sender_at + 192].copy_from_slice(&self.sender_current);
+        out[sender_at + 204..sender_at + 224].copy_from_slice(self.token.as_slice());
+        out[sender_at + 224..sender_at + 256].copy_from_slice(&self.domain);
+        out[sender_at + 256..sender_at + 288].copy_from_slice(&U256::from(self.sender_version).to_be_bytes::<32>());
+        out[sender_at + 288..sender_at + 416].copy_from_slice(&self.sender_new_balance);
+        out
+    }
+}
+
+// Approve public inputs, defaulting to a first approval out of an untouched balance
+struct ApproveInputs {
+    owner_pk: [u8; 64],
+    owner_current: [u8; 128],
+    spender: Address,
+    spender_pk: [u8; 64],
+    token: Address,
+    domain: [u8; 32],
+    balance_version: u64,
+    allowance_version: u64,
+    owner_current_allowance: [u8; 128],
+    owner_new_balance: [u8; 128],
+    owner_allowance: [u8; 128],
+    spender_allowance: [u8; 128],
+    allowance_open: bool,
+}
+
+impl ApproveInputs {
+    fn new(vm: &TestVM, owner_pk: [u8; 64], spender: Address, spender_pk: [u8; 64]) -> Self {
+        Self {
+            owner_pk,
+            owner_current: zero_balance(owner_pk),
+            spender,
+            spender_pk,
+            token: weth(),
+            domain: domain(vm),
+            balance_version: 0,
+            allowance_version: 0,
+            owner_current_allowance: zero_balance(owner_pk),
+            owner_new_balance: [5u8; 128],
+            owner_allowance: ciphertext(g(2), g(3)),
+            spender_allowance: ciphertext(g(2), g(4)),
+            allowance_open: true,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; APPROVE_INPUTS_LEN];
+        out[0..64].copy_from_slice(&self.owner_pk);
+        out[64..192].copy_from_slice(&self.owner_current);
+        out[204..224].copy_from_slice(self.spender.as_slice());
+        out[224..288].copy_from_slice(&self.spender_pk);
+        out[300..320].copy_from_slice(self.token.as_slice());
+        out[320..352].copy_from_slice(&self.domain);
+        out[352..384].copy_from_slice(&U256::from(self.balance_version).to_be_bytes::<32>());
+        out[384..416].copy_from_slice(&U256::from(self.allowance_version).to_be_bytes::<32>());
+        out[416..544].copy_from_slice(&self.owner_current_allowance);
+        out[544..672].copy_from_slice(&self.owner_new_balance);
+        out[672..800].copy_from_slice(&self.owner_allowance);
+        out[800..928].copy_from_slice(&self.spender_allowance);
+        out[959] = self.allowance_open as u8;
+        out
+    }
+}
+
+// Transfer-from public inputs, spending from the allowance set by `ApproveInputs::new`
+struct TransferFromInputs {
+    owner: Address,
+    owner_pk: [u8; 64],
+    receiver: Address,
+    receiver_pk: [u8; 64],
+    spender_pk: [u8; 64],
+    owner_current_allowance: [u8; 128],
+    spender_current_allowance: [u8; 128],
+    token: Address,
+    domain: [u8; 32],
+    allowance_version: u64,
+    owner_new_allowance: [u8; 128],
+    spender_new_allowance: [u8; 128],
+    receiver_amount: [u8; 128],
+    allowance_open: bool,
+}
+
+impl TransferFromInputs {
+    fn new(vm: &TestVM, owner: Address, owner_pk: [u8; 64], receiver: Address, receiver_pk: [u8; 64], spender_pk: [u8; 64]) -> Self {
+        Self {
+            owner,
+            owner_pk,
+            receiver,
+            receiver_pk,
+            spender_pk,
+            owner_current_allowance: ciphertext(g(2), g(3)),
+            spender_current_allowance: ciphertext(g(2), g(4)),
+            token: weth(),
+            domain: domain(vm),
+            allowance_version: 1,
+            owner_new_allowance: [6u8; 128],
+            spender_new_allowance: [7u8; 128],
+            receiver_amount: ciphertext(g(1), g(1)),
+            allowance_open: true,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; TRANSFER_FROM_INPUTS_LEN];
+        out[12..32].copy_from_slice(self.owner.as_slice());
+        out[32..96].copy_from_slice(&self.owner_pk);
+        out[108..128].copy_from_slice(self.receiver.as_slice());
+        out[128..192].copy_from_slice(&self.receiver_pk);
+        out[192..256].copy_from_slice(&self.spender_pk);
+        out[256..384].copy_from_slice(&self.owner_current_allowance);
+        out[384..512].copy_from_slice(&self.spender_current_allowance);
+        out[524..544].copy_from_slice(self.token.as_slice());
+        out[544..576].copy_from_slice(&self.domain);
+        out[576..608].copy_from_slice(&U256::from(self.allowance_version).to_be_bytes::<32>());
+        out[608..736].copy_from_slice(&self.owner_new_allowance);
+        out[736..864].copy_from_slice(&self.spender_new_allowance);
+        out[864..992].copy_from_slice(&self.receiver_amount);
+        out[1023] = self.allowance_open as u8;
+        out
+    }
+}
+
+// Re-key public inputs, one slot per token in `get_token_list` order
+struct RekeyInputs {
+    old_pk: [u8; 64],
+    new_pk: [u8; 64],
+    user: Address,
+    domain: [u8; 32],
+    tokens: [Address; REKEY_SLOTS],
+    versions: [u64; REKEY_SLOTS],
+    old_balances: [[u8; 128]; REKEY_SLOTS],
+    new_balances: [[u8; 128]; REKEY_SLOTS],
+}
+
+impl RekeyInputs {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![0u8; REKEY_INPUTS_LEN];
+        out[0..64].copy_from_slice(&self.old_pk);
+        out[64..128].copy_from_slice(&self.new_pk);
+        out[140..160].copy_from_slice(self.user.as_slice());
+        out[160..192].copy_from_slice(&self.domain);
+        for i in 0..REKEY_SLOTS {
+            let token_at = 192 + 32 * i;
+            out[token_at + 12..token_at + 32].copy_from_slice(self.tokens[i].as_slice());
+            let version_at = 192 + 32 * REKEY_SLOTS + 32 * i;
+            out[version_at..version_at + 32].copy_from_slice(&U256::from(self.versions[i]).to_be_bytes::<32>());
+            let old_at = 192 + 64 * REKEY_SLOTS + 128 * i;
+            out[old_at..old_at + 128].copy_from_slice(&self.old_balances[i]);
+            let new_at = 192 + 192 * REKEY_SLOTS + 128 * i;
+            out[new_at..new_at + 128].copy_from_slice(&self.new_balances[i]);
+        }
+        out
+    }
+}
+
+// n * G on Grumpkin for small n, as 64 bytes
+fn g(n: usize) -> [u8; 64] {
+    let generator = Point { x: G_GENERATOR_X, y: G_GENERATOR_Y };
+    let mut acc = Point::zero();
+    for _ in 0..n {
+        acc = grumpkin::add_points(&acc, &generator);
+    }
+    let mut out = [0u8; 64];
+    out[..32].copy_from_slice(&acc.x);
+    out[32..].copy_from_slice(&acc.y);
+    out
+}
+
+fn ciphertext(x1: [u8; 64], x2: [u8; 64]) -> [u8; 128] {
+    let mut out = [0u8; 128];
+    out[..64].copy_from_slice(&x1);
+    out[64..].copy_from_slice(&x2);
+    out
+}
+
+fn setup(vm: &TestVM) -> ConfidentialERC20 {
+    let mut contract = ConfidentialERC20::from(vm);
+    vm.set_sender(addr(1));
+    contract.init(addr(20), addr(21), addr(22)).unwrap();
+    for verifier in [addr(20), addr(21), addr(22)] {
+        vm.set_code(verifier, vec![0x00]);
+    }
+    contract
+}
+
+// Wait out the timelock and execute every pending change as the owner
+fn execute_pending(vm: &TestVM, contract: &mut ConfidentialERC20) {
+    let pending = contract.pending_changes();
+    let eta = pending.iter().map(|change| change.6).max().unwrap_or_default();
+    vm.set_block_timestamp(eta.to::<u64>());
+    vm.set_sender(addr(1));
+    for change in pending {
+        contract.execute_change(change.0).unwrap();
+    }
+}
+
 #[test]
 fn test_init_sets_owner_and_verifier() {
     let vm = TestVM::default();
     let mut contract = ConfidentialERC20::from(&vm);
 
     vm.set_sender(addr(1));
-    let verifier = addr(2);
-    contract.init(verifier, U256::from(99)).unwrap();
+    contract.init(addr(2), addr(3), addr(4)).unwrap();
 
-    assert_eq!(contract.get_verifier(), verifier);
+    assert_eq!(contract.get_deposit_verifier(), addr(2));
+    assert_eq!(contract.get_withdraw_verifier(), addr(3));
+    assert_eq!(contract.get_transfer_verifier(), addr(4));
     assert_eq!(contract.get_owner(), addr(1));
-    assert_eq!(contract.chain_id.get(), U256::from(99));
+    assert!(contract.is_supported_token(weth()));
 
-    let err = contract.init(verifier, U256::from(99)).unwrap_err();
-    assert!(String::from_utf8_lossy(&err).contains("Already initialized"));
+    let err = contract.init(addr(2), addr(3), addr(4)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::AlreadyInitialized(_)));
 }
 
 #[test]
-fn test_only_owner_can_set_supported_token() {
+fn test_only_verifier_admin_can_set_verifier() {
     let vm = TestVM::default();
-    let mut contract = ConfidentialERC20::from(&vm);
+    let mut contract = setup(&vm);
 
     vm.set_sender(addr(1));
-    contract.init(addr(9), U256::from(1)).unwrap();
+    contract.set_verifier(addr(5), addr(6), addr(7)).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_deposit_verifier(), addr(5));
+    // The previous deposit verifier stays registered as version 1
+    assert_eq!(contract.current_verifier_version(CIRCUIT_DEPOSIT), 2);
+    assert_eq!(contract.get_verifier(CIRCUIT_DEPOSIT, 1), (addr(20), U256::ZERO));
+
+    vm.set_sender(addr(4));
+    let err = contract.set_verifier(addr(8), addr(8), addr(8)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
+}
+
+#[test]
+fn test_verifier_versions_migrate_and_deprecate() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    contract.register_verifier(CIRCUIT_TRANSFER, 2, addr(25)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(25), vec![0x00]);
+    assert_eq!(contract.get_transfer_verifier(), addr(25));
+    let event = last_event::<VerifierRegistered>(&vm);
+    assert_eq!((event.circuit, event.version, event.verifier), (CIRCUIT_TRANSFER, 2, addr(25)));
+
+    let err = contract.register_verifier(CIRCUIT_TRANSFER, 2, addr(26)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidVerifierVersion(_)));
+    let err = contract.register_verifier(8, 1, addr(26)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidCircuit(_)));
+    let err = contract.deprecate_verifier(CIRCUIT_TRANSFER, 2, U256::from(100)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::VerifierVersionInUse(_)));
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    // An in-flight proof for version 1 still verifies against the old verifier
+    let proof = vec![0xAA; 64];
+    let first = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk);
+    let inputs = first.encode();
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.transfer_confidential(1, inputs, proof.clone().into()).unwrap();
 
     vm.set_sender(addr(1));
-    let user = address!("0xCDC41bff86a62716f050622325CC17a317f99404");
-    contract.set_supported_token(user, true).unwrap();
-    assert!(contract.is_supported_token(addr(3)));
+    contract.deprecate_verifier(CIRCUIT_TRANSFER, 1, U256::from(100)).unwrap();
+    assert_eq!(contract.get_verifier(CIRCUIT_TRANSFER, 1), (addr(22), U256::from(100)));
+
+    vm.set_sender(addr(3));
+    vm.set_block_number(101);
+    let inputs = TransferInputs {
+        sender_current: first.sender_new_balance,
+        sender_version: 1,
+        sender_new_balance: [4u8; 128],
+        ..TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk)
+    }
+    .encode();
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential(1, inputs.clone(), proof.clone().into()).unwrap_err();
+    let deprecated = VerifierVersionDeprecated { circuit: CIRCUIT_TRANSFER, version: 1, cutoff_block: U256::from(100) };
+    assert_eq!(err, ConfidentialERC20Error::VerifierVersionDeprecated(deprecated));
+    let err = contract.transfer_confidential(3, inputs.clone(), proof.clone().into()).unwrap_err();
+    let unknown = UnknownVerifierVersion { circuit: CIRCUIT_TRANSFER, version: 3 };
+    assert_eq!(err, ConfidentialERC20Error::UnknownVerifierVersion(unknown));
+
+    // Version 0 resolves to the current verifier
+    vm.mock_static_call(addr(25), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.transfer_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(2));
+}
+
+#[test]
+fn test_timelocked_changes_wait_for_delay_and_list_as_pending() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    // init starts at the minimum delay, and the delay can never go below it
+    assert_eq!(contract.get_timelock_delay(), U256::from(MIN_TIMELOCK_DELAY));
+    let err = contract.set_timelock_delay(U256::ZERO).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidTimelockDelay(_)));
+    let err = contract.set_timelock_delay(U256::from(MIN_TIMELOCK_DELAY - 1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidTimelockDelay(_)));
+    let err = contract.set_timelock_delay(U256::from(MAX_TIMELOCK_DELAY + 1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidTimelockDelay(_)));
+    assert!(contract.pending_changes().is_empty());
+
+    vm.set_block_timestamp(1_000);
+    contract.set_guardian(addr(9)).unwrap();
+    contract.set_verifier(addr(5), addr(6), addr(7)).unwrap();
+    contract.set_supported_token(weth(), false).unwrap();
+    assert_eq!(contract.get_guardian(), Address::ZERO);
+    assert_eq!(contract.get_deposit_verifier(), addr(20));
+    assert!(contract.is_supported_token(weth()));
+    let event = last_event::<ChangeQueued>(&vm);
+    assert_eq!((event.id, event.kind, event.target), (U256::from(4), CHANGE_TOKEN_REMOVAL, weth()));
+
+    let pending = contract.pending_changes();
+    assert_eq!(pending.len(), 5);
+    let eta = U256::from(1_000 + MIN_TIMELOCK_DELAY);
+    assert_eq!(pending[0], (U256::ZERO, CHANGE_GUARDIAN, addr(9), U256::ZERO, U256::ZERO, U256::ZERO, eta));
+    assert_eq!(pending[1], (U256::from(1), CHANGE_VERIFIER, addr(5), U256::from(CIRCUIT_DEPOSIT), U256::ZERO, U256::ZERO, eta));
+
+    let err = contract.execute_change(U256::from(1)).unwrap_err();
+    let not_ready = ChangeNotReady { id: U256::from(1), eta };
+    assert_eq!(err, ConfidentialERC20Error::ChangeNotReady(not_ready));
+
+    vm.set_block_timestamp(eta.to::<u64>());
+    contract.execute_change(U256::from(1)).unwrap();
+    assert_eq!(contract.get_deposit_verifier(), addr(5));
+    let event = last_event::<ChangeExecuted>(&vm);
+    assert_eq!((event.id, event.kind), (U256::from(1), CHANGE_VERIFIER));
+    let err = contract.execute_change(U256::from(1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ChangeNotPending(_)));
+    assert_eq!(contract.pending_changes().len(), 4);
+
+    // A new delay only applies to changes queued after it is executed
+    contract.set_timelock_delay(U256::from(2 * MIN_TIMELOCK_DELAY)).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!((contract.get_guardian(), contract.get_timelock_delay()), (addr(9), U256::from(2 * MIN_TIMELOCK_DELAY)));
+    assert!(!contract.is_supported_token(weth()));
+    assert!(contract.pending_changes().is_empty());
+}
+
+#[test]
+fn test_guardian_cancels_queued_changes() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    contract.set_guardian(addr(9)).unwrap();
+    execute_pending(&vm, &mut contract);
+    contract.set_fee_config(weth(), U256::from(100), U256::ZERO, U256::ZERO).unwrap();
+    contract.set_supported_token(weth(), false).unwrap();
+    let (fee_change, removal) = (U256::from(1), U256::from(2));
 
     vm.set_sender(addr(4));
-    let err = contract.set_supported_token(addr(5), true).unwrap_err();
-    assert!(String::from_utf8_lossy(&err).contains("Not owner"));
+    let err = contract.cancel_change(removal).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotGuardian(_)));
+
+    vm.set_sender(addr(9));
+    contract.cancel_change(fee_change).unwrap();
+    let event = last_event::<ChangeCancelled>(&vm);
+    assert_eq!((event.id, event.kind, event.account), (fee_change, CHANGE_FEE_CONFIG, addr(9)));
+
+    // Execution needs the queuing authority, even once the delay has passed
+    vm.set_block_timestamp(2 * MIN_TIMELOCK_DELAY);
+    let err = contract.execute_change(removal).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
+
+    vm.set_sender(addr(1));
+    let err = contract.execute_change(fee_change).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ChangeNotPending(_)));
+    contract.execute_change(removal).unwrap();
+    assert!(!contract.is_supported_token(weth()));
+    assert!(contract.pending_changes().is_empty());
 }
 
 #[test]
-fn test_register_user_pk_and_balance_read() {
+fn test_only_token_admin_can_set_supported_token() {
     let vm = TestVM::default();
-    let mut contract = ConfidentialERC20::from(&vm);
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(1));
+    let token = addr(50);
+    let err = contract.set_supported_token(token, true).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::TokenNotConfigured(_)));
+
+    contract.set_token_config(token, U256::from(1), 6).unwrap();
+    contract.set_supported_token(token, true).unwrap();
+    assert!(contract.is_supported_token(token));
+    assert_eq!(contract.get_token_config(token), (true, U256::from(1), 6));
+
+    vm.set_sender(addr(4));
+    let err = contract.set_supported_token(token, false).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
+    let err = contract.set_token_config(token, U256::from(2), 6).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
 
     vm.set_sender(addr(1));
-    contract.init(addr(2), U256::from(1)).unwrap();
+    contract.set_supported_token(token, false).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert!(!contract.is_supported_token(token));
+}
+
+#[test]
+fn test_two_step_ownership_transfer() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    contract.transfer_ownership(addr(2)).unwrap();
+    assert_eq!(contract.get_owner(), addr(1));
+    assert_eq!(contract.get_pending_owner(), addr(2));
+
+    vm.set_sender(addr(3));
+    let err = contract.accept_ownership().unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotPendingOwner(_)));
+
+    vm.set_sender(addr(2));
+    contract.accept_ownership().unwrap();
+    assert_eq!(contract.get_owner(), addr(2));
+    assert_eq!(contract.get_pending_owner(), Address::ZERO);
+
+    contract.renounce_ownership().unwrap();
+    assert_eq!(contract.get_owner(), Address::ZERO);
+    let err = contract.grant_role(PAUSER_ROLE, addr(2)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
+
+    // Without an owner the contract still can't be initialized again
+    vm.set_sender(addr(3));
+    let err = contract.init(addr(5), addr(6), addr(7)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::AlreadyInitialized(_)));
+    assert_eq!(contract.get_owner(), Address::ZERO);
+}
+
+#[test]
+fn test_roles_are_granted_revoked_and_renounced() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    assert!(contract.has_role(VERIFIER_ADMIN_ROLE, addr(1)));
 
+    // The ops bot only manages the token list
+    contract.grant_role(TOKEN_ADMIN_ROLE, addr(5)).unwrap();
     vm.set_sender(addr(5));
-    let pk = vec![0xAA; 32];
-    contract.register_user_pk(pk.clone()).unwrap();
+    contract.set_token_config(addr(50), U256::from(1), 6).unwrap();
+    let err = contract.set_verifier(addr(8), addr(8), addr(8)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
+    let err = contract.grant_role(VERIFIER_ADMIN_ROLE, addr(5)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
 
-    let stored = contract.user_pk.get(addr(5));
-    assert_eq!(stored.as_slice(), pk.as_slice());
+    vm.set_sender(addr(1));
+    contract.revoke_role(TOKEN_ADMIN_ROLE, addr(5)).unwrap();
+    assert!(!contract.has_role(TOKEN_ADMIN_ROLE, addr(5)));
 
-    let (x1, x2) = contract.balance_of_enc(addr(8), addr(5));
-    assert_eq!(x1, [0u8; 32]);
-    assert_eq!(x2, [0u8; 32]);
+    contract.renounce_role(VERIFIER_ADMIN_ROLE).unwrap();
+    let err = contract.set_verifier(addr(8), addr(8), addr(8)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
 }
 
 #[test]
-fn test_verify_proof_rejects_missing_pks() {
+fn test_pause_is_gated_to_owner_and_pauser() {
     let vm = TestVM::default();
-    let mut contract = ConfidentialERC20::from(&vm);
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(6));
+    let err = contract.pause().unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
 
     vm.set_sender(addr(1));
-    contract.init(addr(99), U256::from(1)).unwrap();
+    contract.grant_role(PAUSER_ROLE, addr(6)).unwrap();
+    contract.revoke_role(PAUSER_ROLE, addr(1)).unwrap();
 
-    vm.set_sender(addr(10));
-    contract.register_user_pk(vec![1u8; 32]).unwrap();
+    // Incident responder pauses, the owner can still unpause without the role
+    vm.set_sender(addr(6));
+    contract.pause().unwrap();
+    assert!(contract.paused());
+    assert!(contract.is_operation_paused(OP_WITHDRAW));
+    let err = contract.set_operation_paused(OP_ALL, true).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidOperation(_)));
 
-    let res = contract._verify_proof(
-        addr(1),
-        addr(10),
-        addr(11),
-        &vec![0u8; 64],
-        &vec![0x01, 0x02, 0x03],
-    );
-    assert!(res.is_err());
-    assert!(String::from_utf8_lossy(&res.unwrap_err()).contains("to pk not registered"));
+    vm.set_sender(addr(1));
+    contract.unpause().unwrap();
+    assert!(!contract.paused());
 }
 
 #[test]
-fn test_verify_proof_success_with_mocked_verifier() {
+fn test_pause_blocks_operations_globally_per_operation_and_per_token() {
     let vm = TestVM::default();
-    let mut contract = ConfidentialERC20::from(&vm);
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    vm.set_sender(addr(1));
+    contract.pause().unwrap();
+    vm.set_sender(addr(3));
+    let err = contract.deposit(0, inputs.clone(), proof.clone().into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::EnforcedPause(_)));
+    let err = contract.apply_pending(weth()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::EnforcedPause(_)));
 
     vm.set_sender(addr(1));
-    let verifier = addr(100);
-    contract.init(verifier, U256::from(77)).unwrap();
+    contract.unpause().unwrap();
+    contract.set_operation_paused(OP_DEPOSIT, true).unwrap();
+    vm.set_sender(addr(3));
+    let err = contract.deposit(0, inputs.clone(), proof.clone().into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::OperationPaused(_)));
 
-    // Register both pks
-    vm.set_sender(addr(10));
-    contract.register_user_pk(vec![1u8; 32]).unwrap();
-    vm.set_sender(addr(11));
-    contract.register_user_pk(vec![2u8; 32]).unwrap();
+    vm.set_sender(addr(1));
+    contract.set_operation_paused(OP_DEPOSIT, false).unwrap();
+    contract.set_token_paused(weth(), true).unwrap();
+    assert!(contract.is_token_paused(weth()));
+    vm.set_sender(addr(3));
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::TokenPaused(_)));
+}
 
-    // Mock ANY static call to verifier
-    vm.mock_static_call(verifier, vec![], Ok(vec![0u8; 31].into_iter().chain(vec![1u8]).collect()));
+#[test]
+fn test_withdraw_only_mode_keeps_exits_open() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
 
-    let res = contract._verify_proof(
-        addr(1),
-        addr(10),
-        addr(11),
-        &vec![0u8; 64],
-        &vec![0xAA; 64],
-    );
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    vm.set_sender(addr(1));
+    contract.pause().unwrap();
+    contract.set_withdraw_only().unwrap();
+    assert!(!contract.paused());
+    assert!(contract.is_operation_paused(OP_DEPOSIT));
+    assert!(contract.is_operation_paused(OP_TRANSFER));
+    assert!(!contract.is_operation_paused(OP_WITHDRAW));
+
+    vm.set_sender(addr(3));
+    let err = contract.transfer_confidential(0, vec![0u8; TRANSFER_INPUTS_LEN], vec![0xAA; 64].into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::OperationPaused(_)));
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let transfer = transferCall { to: addr(3), amount: U256::from(10_000_000) }.abi_encode();
+    vm.mock_call(weth(), transfer, Ok(abi_bool(true)));
+    contract.withdraw(0, inputs, proof.into()).unwrap();
+}
+
+#[test]
+fn test_frozen_accounts_cannot_receive_or_deposit() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    vm.set_sender(addr(6));
+    let err = contract.set_frozen(addr(4), true).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::MissingRole(_)));
+
+    vm.set_sender(addr(1));
+    contract.grant_role(COMPLIANCE_ROLE, addr(6)).unwrap();
+    vm.set_sender(addr(6));
+    contract.set_frozen(addr(4), true).unwrap();
+    assert!(contract.is_frozen(addr(4)));
+    let event = last_event::<AccountFrozen>(&vm);
+    assert_eq!((event.account, event.sender), (addr(4), addr(6)));
+
+    // The frozen receiver blocks incoming transfers
+    vm.set_sender(addr(3));
+    let inputs = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential(0, inputs.clone(), proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::FrozenAccount(FrozenAccount { account: addr(4) }));
+
+    // and its own deposits
+    vm.set_sender(addr(4));
+    let deposit = DepositWithdrawInputs::new(&vm, receiver_pk, addr(4)).encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&deposit, &proof), Ok(abi_bool(true)));
+    let err = contract.deposit(0, deposit, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::FrozenAccount(FrozenAccount { account: addr(4) }));
+    assert!(!contract.guard.locked.get());
+
+    vm.set_sender(addr(6));
+    contract.set_frozen(addr(4), false).unwrap();
+    assert!(!contract.is_frozen(addr(4)));
+    assert_eq!(last_event::<AccountUnfrozen>(&vm).account, addr(4));
+
+    vm.set_sender(addr(3));
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.transfer_confidential(0, inputs, proof.into()).unwrap();
+}
+
+#[test]
+fn test_policy_contract_is_consulted_before_transfers() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    contract.set_policy(addr(30)).unwrap();
+    assert_eq!(contract.get_policy(), addr(30));
+    assert_eq!(last_event::<PolicyUpdated>(&vm).new_policy, addr(30));
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    let inputs = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk).encode();
+    let is_allowed = isAllowedCall { from: addr(3), to: addr(4), token: weth() }.abi_encode();
+    let transfer = |contract: &mut ConfidentialERC20, proof: Vec<u8>| {
+        vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+        contract.transfer_confidential(0, inputs.clone(), proof.into())
+    };
+
+    vm.mock_static_call(addr(30), is_allowed.clone(), Ok(abi_bool(false)));
+    let err = transfer(&mut contract, vec![0xAA; 64]).unwrap_err();
+    let denied = PolicyDenied { from: addr(3), to: addr(4), token: weth() };
+    assert_eq!(err, ConfidentialERC20Error::PolicyDenied(denied));
+
+    // A reverting policy fails closed
+    vm.mock_static_call(addr(30), is_allowed.clone(), Err(vec![0xde, 0xad]));
+    let err = transfer(&mut contract, vec![0xBB; 64]).unwrap_err();
+    let reverted = PolicyCallReverted { policy: addr(30), data: vec![0xde, 0xad].into() };
+    assert_eq!(err, ConfidentialERC20Error::PolicyCallReverted(reverted));
+
+    vm.mock_static_call(addr(30), is_allowed, Ok(abi_bool(true)));
+    transfer(&mut contract, vec![0xCC; 64]).unwrap();
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+}
+
+#[test]
+fn test_register_user_pk_and_balance_read() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(5));
+    let pk = [0xAA; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    assert_eq!(contract.get_user_pk(addr(5)), pk);
+    assert_eq!(contract.balance_of_enc(weth(), addr(5)), zero_balance(pk));
+    assert_eq!(contract.balance_version(weth(), addr(5)), U256::ZERO);
+
+    // Every token reads as an encryption of 0, registered or not in the allowlist
+    assert_eq!(contract.balance_of_enc(addr(50), addr(5)), zero_balance(pk));
+    assert_eq!(contract.balance_of_enc(weth(), addr(6)), [0u8; 128]);
+
+    let err = contract.register_user_pk(pk).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::UserAlreadyRegistered(_)));
+}
+
+#[test]
+fn test_verify_proof_rejects_non_contract_verifier() {
+    let vm = TestVM::default();
+    let contract = setup(&vm);
+
+    let res = contract._verify_proof(&[0u8; 64], vec![0x01].into(), addr(99));
+    assert_eq!(res.unwrap_err(), VerifyProofError::NotAContract);
+}
+
+#[test]
+fn test_verify_proof_success_with_mocked_verifier() {
+    let vm = TestVM::default();
+    let contract = setup(&vm);
+
+    let inputs = [7u8; 64];
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let res = contract._verify_proof(&inputs, proof.into(), addr(20));
     assert!(res.is_ok());
 }
 
 #[test]
-fn test_extract_amount_from_proof_inputs() {
+fn test_verify_proof_rejects_false_result() {
     let vm = TestVM::default();
-    let contract = ConfidentialERC20::from(&vm);
+    let contract = setup(&vm);
+
+    let inputs = [7u8; 64];
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(false)));
+
+    let res = contract._verify_proof(&inputs, proof.into(), addr(20));
+    assert_eq!(res.unwrap_err(), VerifyProofError::ReturnedFalse);
+}
+
+#[test]
+fn test_verify_proof_rejects_malformed_return_data() {
+    let vm = TestVM::default();
+    let contract = setup(&vm);
+
+    let inputs = [7u8; 64];
+    let proof = vec![0xAA; 64];
+
+    // Unmocked calls return empty data
+    let res = contract._verify_proof(&inputs, proof.clone().into(), addr(20));
+    assert_eq!(res.unwrap_err(), VerifyProofError::BadReturnLength(0));
+
+    // A non-canonical bool word is not accepted as `true`
+    let mut dirty = abi_bool(true);
+    dirty[0] = 1;
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(dirty.clone()));
+    let res = contract._verify_proof(&inputs, proof.into(), addr(20));
+    let word: [u8; 32] = dirty.try_into().unwrap();
+    assert_eq!(res.unwrap_err(), VerifyProofError::NonCanonicalBool(word));
+    let err = ConfidentialERC20Error::from(VerifyProofError::NonCanonicalBool(word));
+    assert_eq!(err, ConfidentialERC20Error::VerifierNonCanonicalReturn(VerifierNonCanonicalReturn { word: word.into() }));
+}
+
+#[test]
+fn test_verify_proof_surfaces_revert() {
+    let vm = TestVM::default();
+    let contract = setup(&vm);
+
+    let inputs = [7u8; 64];
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Err(vec![0xde, 0xad]));
+
+    let res = contract._verify_proof(&inputs, proof.into(), addr(20));
+    assert_eq!(res.unwrap_err(), VerifyProofError::CallReverted(vec![0xde, 0xad]));
+}
+
+#[test]
+fn test_deposit_returns_verifier_error() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(false)));
+
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ProofRejected(_)));
+    assert!(!contract.guard.locked.get());
+}
 
-    let mut proof_inputs = vec![0u8; 64];
-    proof_inputs[31] = 5;
+#[test]
+fn test_decode_deposit_withdraw_proof_inputs() {
+    let vm = TestVM::default();
+    let contract = ConfidentialERC20::from(&vm);
 
-    let amt = contract._extract_amount_from_proof_inputs(&proof_inputs).unwrap();
-    assert_eq!(amt, U256::from(5));
+    let inputs = DepositWithdrawInputs {
+        pk: [1u8; 64],
+        current: [2u8; 128],
+        counterparty: addr(3),
+        token: addr(4),
+        amount: 5,
+        domain: [7u8; 32],
+        version: 9,
+        auditor_pk: [8u8; 64],
+        new_balance: [6u8; 128],
+        auditor_amount: [10u8; 128],
+    }
+    .encode();
+    let decoded = contract
+        ._decode_deposit_withdraw_proof_inputs(inputs.try_into().unwrap());
 
-    let err = contract._extract_amount_from_proof_inputs(&vec![1, 2, 3]).unwrap_err();
-    assert!(String::from_utf8_lossy(&err).contains("Invalid proof inputs"));
+    assert_eq!(decoded.user_pubkey, [1u8; 64]);
+    assert_eq!(decoded.counterparty, addr(3));
+    assert_eq!(decoded.token, addr(4));
+    assert_eq!(decoded.amount, U256::from(5));
+    assert_eq!(decoded.domain, [7u8; 32]);
+    assert_eq!(decoded.balance_version, U256::from(9));
+    assert_eq!(decoded.auditor_pubkey, [8u8; 64]);
+    assert_eq!(decoded.auditor_amount.to_bytes(), [10u8; 128]);
 }
 
 #[test]
@@ -138,35 +1029,1579 @@ fn test_non_reentrancy_guard_blocks_second_call() {
 
     contract.guard.locked.set(true);
     let err = contract._non_reentrant().unwrap_err();
-    assert!(String::from_utf8_lossy(&err).contains("Reentrant call"));
+    assert!(matches!(err, ConfidentialERC20Error::ReentrantCall(_)));
 }
 
 #[test]
 fn test_deposit_with_mocked_erc20_transferfrom() {
     let vm = TestVM::default();
-    let mut contract = ConfidentialERC20::from(&vm);
+    let mut contract = setup(&vm);
 
-    vm.set_sender(addr(1));
-    contract.init(addr(2), U256::from(1)).unwrap();
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
 
-    // Allow token
-    vm.set_sender(addr(1));
-    contract.set_supported_token(addr(50), true).unwrap();
+    let new_balance = [2u8; 128];
+    let inputs = DepositWithdrawInputs { new_balance, ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
 
     // Mock ERC20::transferFrom() call
-    vm.mock_call(
-        addr(50),
-        vec![],
-        Ok(vec![0u8; 31].into_iter().chain(vec![1u8]).collect()),
-    );
+    let transfer_from = transferFromCall {
+        from: addr(3),
+        to: vm.contract_address(),
+        amount: U256::from(10_000_000),
+    }
+    .abi_encode();
+    vm.mock_call(weth(), transfer_from, Ok(abi_bool(true)));
+
+    contract.deposit(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), new_balance);
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+
+    // The log alone is enough to rebuild the encrypted balance
+    let event = last_event::<Deposit>(&vm);
+    assert_eq!(event.user_address, addr(3));
+    assert_eq!(event.amount, U256::from(10_000_000));
+    assert_eq!(event.balance_version, U256::from(1));
+    assert_eq!(event.new_balance.as_ref(), new_balance);
+}
+
+#[test]
+fn test_deposit_for_credits_beneficiary_and_charges_payer() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
 
     vm.set_sender(addr(3));
-    let res = contract.deposit(
-        addr(50),
-        U256::from(10),
-        [1u8; 32],
-        [2u8; 32],
-        addr(3),
-    );
-    assert!(res.is_ok());
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    // Beneficiary addr(3) proves the deposit, binding addr(4) as payer
+    let new_balance = [2u8; 128];
+    let inputs = DepositWithdrawInputs { new_balance, ..DepositWithdrawInputs::new(&vm, pk, addr(4)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let transfer_from = transferFromCall {
+        from: addr(4),
+        to: vm.contract_address(),
+        amount: U256::from(10_000_000),
+    }
+    .abi_encode();
+    vm.mock_call(weth(), transfer_from, Ok(abi_bool(true)));
+
+    vm.set_sender(addr(4));
+    contract.deposit_for(addr(3), 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), new_balance);
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+    assert_eq!(contract.balance_version(weth(), addr(4)), U256::ZERO);
+}
+
+#[test]
+fn test_withdraw_to_sends_tokens_to_bound_recipient() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    let new_balance = [2u8; 128];
+    let inputs = DepositWithdrawInputs { new_balance, ..DepositWithdrawInputs::new(&vm, pk, addr(6)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let transfer = transferCall { to: addr(6), amount: U256::from(10_000_000) }.abi_encode();
+    vm.mock_call(weth(), transfer, Ok(abi_bool(true)));
+
+    contract.withdraw_to(addr(6), 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), new_balance);
+    assert_eq!(contract.balance_version(weth(), addr(6)), U256::ZERO);
+}
+
+#[test]
+fn test_withdraw_releases_guard_when_payout_fails() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let transfer = transferCall { to: addr(3), amount: U256::from(10_000_000) }.abi_encode();
+    vm.mock_call(weth(), transfer, Ok(abi_bool(false)));
+
+    let err = contract.withdraw(0, inputs, proof.into()).unwrap_err();
+    let failed = Erc20TransferFailed { token: weth(), to: addr(3), amount: U256::from(10_000_000) };
+    assert_eq!(err, ConfidentialERC20Error::Erc20TransferFailed(failed));
+    assert!(!contract.guard.locked.get());
+}
+
+#[test]
+fn test_custody_delta_is_checked_against_proven_amount() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    let token = addr(50);
+    contract.set_token_config(token, U256::from(1), 6).unwrap();
+    contract.set_supported_token(token, true).unwrap();
+    let err = contract.set_token_transfer_mode(token, false, true, U256::from(MAX_TRANSFER_TOLERANCE + 1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidTransferTolerance(_)));
+    // Only rebasing tokens may tolerate a gap
+    let err = contract.set_token_transfer_mode(token, false, false, U256::from(1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::InvalidTransferTolerance(_)));
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let proof = vec![0xAA; 64];
+
+    // Custody does not move: the token kept the whole transfer
+    let balance_of = balanceOfCall { account: vm.contract_address() }.abi_encode();
+    vm.mock_static_call(token, balance_of, Ok(U256::from(500).to_be_bytes::<32>().to_vec()));
+    let inputs = DepositWithdrawInputs { token, ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.deposit(0, inputs, proof.clone().into()).unwrap_err();
+    let mismatch = BalanceDeltaMismatch { token, expected: U256::from(10), actual: U256::ZERO };
+    assert_eq!(err, ConfidentialERC20Error::BalanceDeltaMismatch(mismatch));
+
+    // A rebasing token's rounding gap is accepted up to its tolerance
+    vm.set_sender(addr(1));
+    contract.set_token_transfer_mode(token, false, true, U256::from(10)).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_token_transfer_mode(token), (false, true, U256::from(10)));
+    vm.set_sender(addr(3));
+    let inputs = DepositWithdrawInputs { token, new_balance: [3u8; 128], ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.deposit(0, inputs, proof.clone().into()).unwrap();
+
+    // Fee-on-transfer tokens refuse deposits
+    vm.set_sender(addr(1));
+    contract.set_token_transfer_mode(token, true, false, U256::ZERO).unwrap();
+    execute_pending(&vm, &mut contract);
+    let event = last_event::<TokenTransferModeUpdated>(&vm);
+    assert!(event.fee_on_transfer && !event.rebasing);
+    vm.set_sender(addr(3));
+    let inputs = DepositWithdrawInputs {
+        token,
+        current: [3u8; 128],
+        version: 1,
+        ..DepositWithdrawInputs::new(&vm, pk, addr(3))
+    }
+    .encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::FeeOnTransferToken(FeeOnTransferToken { token }));
+}
+
+#[test]
+fn test_custody_report_tracks_liability_and_sweep_moves_only_excess() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let proof = vec![0xAA; 64];
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.deposit(0, inputs, proof.clone().into()).unwrap();
+    let inputs = DepositWithdrawInputs {
+        current: [2u8; 128],
+        amount: 4,
+        version: 1,
+        new_balance: [3u8; 128],
+        ..DepositWithdrawInputs::new(&vm, pk, addr(3))
+    }
+    .encode();
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.withdraw(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.total_deposited(weth()), U256::from(10_000_000));
+    assert_eq!(contract.total_withdrawn(weth()), U256::from(4_000_000));
+
+    let err = contract.custody_report(weth()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::CustodyUnmeasurable(_)));
+
+    // 7 units were sent to the contract by mistake
+    let balance_of = balanceOfCall { account: vm.contract_address() }.abi_encode();
+    vm.mock_static_call(weth(), balance_of.clone(), Ok(U256::from(6_000_007).to_be_bytes::<32>().to_vec()));
+    let report = contract.custody_report(weth()).unwrap();
+    let liability = U256::from(6_000_000);
+    assert_eq!(report, (U256::from(10_000_000), U256::from(4_000_000), U256::ZERO, liability, U256::from(6_000_007), true));
+
+    let err = contract.sweep_excess(weth(), addr(9)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
+    vm.set_sender(addr(1));
+    contract.sweep_excess(weth(), addr(9)).unwrap();
+    let event = last_event::<ExcessSwept>(&vm);
+    assert_eq!((event.token, event.to, event.amount), (weth(), addr(9), U256::from(7)));
+
+    vm.mock_static_call(weth(), balance_of, Ok(U256::from(5_999_999).to_be_bytes::<32>().to_vec()));
+    assert!(!contract.custody_report(weth()).unwrap().5);
+    let err = contract.sweep_excess(weth(), addr(9)).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::NoExcess(NoExcess { token: weth() }));
+
+    // A rebasing token's excess is the holders' yield
+    let balance_of = balanceOfCall { account: vm.contract_address() }.abi_encode();
+    vm.mock_static_call(weth(), balance_of, Ok(U256::from(6_500_000).to_be_bytes::<32>().to_vec()));
+    contract.set_token_transfer_mode(weth(), false, true, U256::ZERO).unwrap();
+    execute_pending(&vm, &mut contract);
+    let err = contract.sweep_excess(weth(), addr(9)).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::RebasingToken(RebasingToken { token: weth() }));
+}
+
+#[test]
+fn test_deposit_with_permit_and_permit2_pull_without_approve() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let proof = vec![0xAA; 64];
+    let amount = U256::from(10_000_000);
+    let (deadline, r, s) = (U256::from(1_000), FixedBytes::from([1u8; 32]), FixedBytes::from([2u8; 32]));
+
+    // A front-run permit reverts, but the allowance it granted is still there
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let permit = permitCall { owner: addr(3), spender: vm.contract_address(), value: amount, deadline, v: 27, r, s };
+    vm.mock_call(weth(), permit.abi_encode(), Err(vec![0x01]));
+    let transfer_from = transferFromCall { from: addr(3), to: vm.contract_address(), amount }.abi_encode();
+    vm.mock_call(weth(), transfer_from, Ok(abi_bool(true)));
+    contract.deposit_with_permit(0, inputs, proof.clone().into(), deadline, 27, r, s).unwrap();
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+
+    let permit2 = Address::from_str(PERMIT2_ADDRESS).unwrap();
+    let signature = vec![0x55; 65];
+    let permit_transfer = |current: [u8; 128], new_balance: [u8; 128]| {
+        let inputs = DepositWithdrawInputs {
+            current,
+            version: 1,
+            new_balance,
+            ..DepositWithdrawInputs::new(&vm, pk, addr(3))
+        }
+        .encode();
+        vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+        inputs
+    };
+    let calldata = permitTransferFromCall {
+        permit: PermitTransferFrom { permitted: TokenPermissions { token: weth(), amount }, nonce: U256::from(7), deadline },
+        transferDetails: SignatureTransferDetails { to: vm.contract_address(), requestedAmount: amount },
+        owner: addr(3),
+        signature: signature.clone().into(),
+    }
+    .abi_encode();
+
+    vm.mock_call(permit2, calldata.clone(), Err(vec![0x02]));
+    let inputs = permit_transfer([2u8; 128], [3u8; 128]);
+    let err = contract
+        .deposit_with_permit2(0, inputs, proof.clone().into(), U256::from(7), deadline, signature.clone().into())
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::Permit2CallReverted(Permit2CallReverted { data: vec![0x02].into() }));
+
+    vm.mock_call(permit2, calldata, Ok(Vec::new()));
+    let inputs = permit_transfer([2u8; 128], [4u8; 128]);
+    contract.deposit_with_permit2(0, inputs, proof.into(), U256::from(7), deadline, signature.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), [4u8; 128]);
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(2));
+}
+
+#[test]
+fn test_deposit_eth_wraps_exact_msg_value() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let proof = vec![0xAA; 64];
+
+    // The attached value must match the proof amount times the scale factor
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_value(U256::from(9_999_999));
+    let err = contract.deposit_eth(0, inputs, proof.clone().into()).unwrap_err();
+    let mismatch = EthValueMismatch { expected: U256::from(10_000_000), value: U256::from(9_999_999) };
+    assert_eq!(err, ConfidentialERC20Error::EthValueMismatch(mismatch));
+
+    let inputs = DepositWithdrawInputs { new_balance: [3u8; 128], ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.mock_call(weth(), depositCall {}.abi_encode(), Ok(Vec::new()));
+    vm.set_value(U256::from(10_000_000));
+    contract.deposit_eth(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), [3u8; 128]);
+    let event = last_event::<Deposit>(&vm);
+    assert_eq!((event.payer, event.amount), (addr(3), U256::from(10_000_000)));
+}
+
+#[test]
+fn test_withdraw_eth_unwraps_and_pays_native_eth() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_token_config(addr(50), U256::from(1), 6).unwrap();
+    contract.set_supported_token(addr(50), true).unwrap();
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let proof = vec![0xAA; 64];
+
+    // Only WETH can be withdrawn as native ETH
+    let inputs = DepositWithdrawInputs { token: addr(50), ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.withdraw_eth(0, inputs, proof.clone().into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::NotWrappedNative(NotWrappedNative { token: addr(50) }));
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.mock_call(weth(), withdrawCall { amount: U256::from(10_000_000) }.abi_encode(), Ok(Vec::new()));
+    vm.set_balance(vm.contract_address(), U256::from(10_000_000));
+    contract.withdraw_eth(0, inputs, proof.into()).unwrap();
+    assert_eq!(vm.balance(addr(3)), U256::from(10_000_000));
+    assert_eq!(vm.balance(vm.contract_address()), U256::ZERO);
+    let event = last_event::<Withdraw>(&vm);
+    assert_eq!((event.recipient, event.amount), (addr(3), U256::from(10_000_000)));
+
+    let err = contract.receive().unwrap_err();
+    assert_eq!(err, UnexpectedEther { sender: addr(3) }.abi_encode());
+}
+
+#[test]
+fn test_fees_are_charged_on_the_plain_leg_and_claimed() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    // 1% + 1_000 units per operation
+    contract.set_fee_config(weth(), U256::from(100), U256::from(1_000), U256::ZERO).unwrap();
+    execute_pending(&vm, &mut contract);
+    contract.set_fee_recipient(addr(30)).unwrap();
+    assert_eq!(contract.quote_fee(weth(), U256::from(10_000_000)), U256::from(101_000));
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    // Deposit pulls the fee on top of the credited amount
+    let deposit = DepositWithdrawInputs::new(&vm, pk, addr(3));
+    let inputs = deposit.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let transfer_from = transferFromCall {
+        from: addr(3),
+        to: vm.contract_address(),
+        amount: U256::from(10_101_000),
+    }
+    .abi_encode();
+    vm.mock_call(weth(), transfer_from, Ok(abi_bool(true)));
+    contract.deposit(0, inputs, proof.into()).unwrap();
+    assert_eq!(last_event::<Deposit>(&vm).amount, U256::from(10_000_000));
+
+    // Withdraw pays out the amount minus the fee
+    let inputs = DepositWithdrawInputs {
+        current: deposit.new_balance,
+        version: 1,
+        new_balance: [3u8; 128],
+        ..DepositWithdrawInputs::new(&vm, pk, addr(3))
+    }
+    .encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let transfer = transferCall { to: addr(3), amount: U256::from(9_899_000) }.abi_encode();
+    vm.mock_call(weth(), transfer, Ok(abi_bool(true)));
+    contract.withdraw(0, inputs, proof.into()).unwrap();
+
+    assert_eq!(contract.accrued_fees(weth()), U256::from(202_000));
+    let event = last_event::<FeesAccrued>(&vm);
+    assert_eq!((event.account, event.fee, event.total_accrued), (addr(3), U256::from(101_000), U256::from(202_000)));
+
+    let err = contract.claim_fees(weth()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
+
+    vm.set_sender(addr(30));
+    let payout = transferCall { to: addr(30), amount: U256::from(202_000) }.abi_encode();
+    vm.mock_call(weth(), payout, Ok(abi_bool(true)));
+    contract.claim_fees(weth()).unwrap();
+    assert_eq!(contract.accrued_fees(weth()), U256::ZERO);
+    assert_eq!(last_event::<FeesClaimed>(&vm).amount, U256::from(202_000));
+}
+
+#[test]
+fn test_fee_config_is_owner_only_bounded_and_capped() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let err = contract.set_fee_config(weth(), U256::from(MAX_FEE_BPS + 1), U256::ZERO, U256::ZERO).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::InvalidFeeBps(InvalidFeeBps { fee_bps: U256::from(MAX_FEE_BPS + 1) }));
+    let err = contract.set_fee_config(addr(50), U256::from(10), U256::ZERO, U256::ZERO).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::TokenNotConfigured(_)));
+
+    contract.set_fee_config(weth(), U256::from(MAX_FEE_BPS), U256::from(5), U256::from(50)).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_fee_config(weth()), (U256::from(MAX_FEE_BPS), U256::from(5), U256::from(50)));
+    assert_eq!(contract.quote_fee(weth(), U256::from(100)), U256::from(15));
+    assert_eq!(contract.quote_fee(weth(), U256::from(10_000)), U256::from(50));
+
+    vm.set_sender(addr(4));
+    let err = contract.set_fee_config(weth(), U256::ZERO, U256::ZERO, U256::ZERO).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
+    let err = contract.set_fee_recipient(addr(4)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NotOwner(_)));
+}
+
+#[test]
+fn test_deposit_for_and_withdraw_to_reject_unbound_counterparty() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    // Deposit proof binds addr(4) as payer, but addr(5) submits it
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(4)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(5));
+    let err = contract.deposit_for(addr(3), 0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::PayerMismatch(PayerMismatch { payer: addr(4) }));
+
+    // Withdraw proof binds addr(6) as recipient, but addr(7) is requested
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(6)).encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    let err = contract.withdraw_to(addr(7), 0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::RecipientMismatch(_)));
+}
+
+#[test]
+fn test_deposit_replay_is_rejected_by_nullifier() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let nullifier = keccak256([addr(20).as_slice(), &proof, &inputs].concat());
+    assert!(!contract.is_nullifier_used(nullifier));
+
+    contract.deposit(0, inputs.clone(), proof.clone().into()).unwrap();
+    assert!(contract.is_nullifier_used(nullifier));
+
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NullifierAlreadyUsed(_)));
+}
+
+#[test]
+fn test_deposit_rejects_foreign_domain() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    assert_eq!(contract.domain_separator(), FixedBytes::from(domain(&vm)));
+
+    // Same proof built for another chain
+    let mut foreign = domain(&vm);
+    foreign[11] ^= 1;
+    let inputs = DepositWithdrawInputs { domain: foreign, ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::DomainMismatch(_)));
+}
+
+#[test]
+fn test_deposit_rejects_stale_balance_version() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(0));
+
+    let inputs = DepositWithdrawInputs { version: 1, ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::StaleBalanceVersion(_)));
+}
+
+#[test]
+fn test_grumpkin_point_addition() {
+    let generator = Point::from_bytes(g(1));
+    let doubled = grumpkin::add_points(&generator, &generator);
+    assert_eq!(
+        U256::from_be_bytes(doubled.x),
+        U256::from_str("0x06ce1b0827aafa85ddeb49cdaa36306d19a74caa311e13d46d8bc688cdbffffe").unwrap()
+    );
+    assert_eq!(
+        U256::from_be_bytes(doubled.y),
+        U256::from_str("0x1c122f81a3a14964909ede0ba2a6855fc93faf6fa1a788bf467be7e7a43f80ac").unwrap()
+    );
+
+    let tripled = grumpkin::add_points(&doubled, &generator);
+    assert_eq!(
+        U256::from_be_bytes(tripled.x),
+        U256::from_str("0x2941b0928df1b9480273773b36397da3e495430a2a7a3857661bc7a446c94f4d").unwrap()
+    );
+
+    // P + O = P and P + (-P) = O
+    assert!(grumpkin::add_points(&Point::zero(), &generator) == generator);
+    let neg_y = grumpkin::MODULUS - U256::from_be_bytes(generator.y);
+    let negated = Point { x: generator.x, y: neg_y.to_be_bytes() };
+    assert!(grumpkin::add_points(&generator, &negated).is_infinity());
+}
+
+#[test]
+fn test_transfer_accumulates_into_receiver_pending() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    let proof = vec![0xAA; 64];
+    let first = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk);
+    let second = TransferInputs {
+        sender_current: first.sender_new_balance,
+        sender_version: 1,
+        sender_new_balance: [4u8; 128],
+        ..TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk)
+    };
+    for inputs in [first.encode(), second.encode()] {
+        vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+        contract.transfer_confidential(0, inputs, proof.clone().into()).unwrap();
+    }
+
+    // Receiver's spendable balance is untouched, both amounts sit in pending
+    assert_eq!(contract.balance_of_enc(weth(), addr(4)), zero_balance(receiver_pk));
+    assert_eq!(contract.balance_version(weth(), addr(4)), U256::from(0));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), ciphertext(g(2), g(2)));
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(2));
+
+    let event = last_event::<TransferConfidential>(&vm);
+    assert_eq!(event.sender_balance_version, U256::from(2));
+    assert_eq!(event.sender_new_balance.as_ref(), [4u8; 128]);
+    assert_eq!(event.receiver_amount.as_ref(), ciphertext(g(1), g(1)));
+    assert_eq!(event.receiver_new_pending.as_ref(), ciphertext(g(2), g(2)));
+}
+
+#[test]
+fn test_audited_transfer_requires_auditor_key_and_emits_auditor_amount() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let err = contract.set_auditor_key(addr(50), g(11)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::TokenNotConfigured(_)));
+    contract.set_auditor_key(weth(), g(11)).unwrap();
+    assert_eq!(contract.get_auditor_key(weth()), [0u8; 64]);
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_auditor_key(weth()), g(11));
+    assert_eq!(last_event::<AuditorKeyUpdated>(&vm).auditor_pk.as_ref(), g(11));
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    // A proof that left the amount unaudited is refused
+    let inputs = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::AuditorKeyMismatch(AuditorKeyMismatch { token: weth() }));
+
+    let inputs = TransferInputs {
+        auditor_pk: g(11),
+        auditor_amount: ciphertext(g(1), g(3)),
+        ..TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk)
+    }
+    .encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.transfer_confidential(0, inputs, proof.into()).unwrap();
+
+    let event = last_event::<TransferConfidential>(&vm);
+    assert_eq!(event.auditor_amount.as_ref(), ciphertext(g(1), g(3)));
+
+    // Clearing the key returns the token to unaudited proofs
+    vm.set_sender(addr(1));
+    contract.set_auditor_key(weth(), [0u8; 64]).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_auditor_key(weth()), [0u8; 64]);
+}
+
+#[test]
+fn test_audited_token_rejects_batch_and_mismatched_deposit() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_batch_transfer_verifier(addr(24)).unwrap();
+    vm.set_code(addr(24), vec![0x00]);
+    contract.set_auditor_key(weth(), g(11)).unwrap();
+    execute_pending(&vm, &mut contract);
+
+    let sender_pk = g(5);
+    vm.set_sender(addr(4));
+    contract.register_user_pk(g(7)).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    let inputs = BatchTransferInputs {
+        receivers: vec![(addr(4), g(7), ciphertext(g(1), g(1)))],
+        sender_pk,
+        sender_current: zero_balance(sender_pk),
+        token: weth(),
+        domain: domain(&vm),
+        sender_version: 0,
+        sender_new_balance: [3u8; 128],
+    }
+    .encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential_batch(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::AuditedToken(AuditedToken { token: weth() }));
+
+    let inputs = DepositWithdrawInputs {
+        auditor_pk: g(12),
+        ..DepositWithdrawInputs::new(&vm, sender_pk, addr(3))
+    }
+    .encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::AuditorKeyMismatch(AuditorKeyMismatch { token: weth() }));
+    assert!(!contract.guard.locked.get());
+}
+
+#[test]
+fn test_transfer_reverts_with_abi_encoded_custom_error() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    // Proof built against a stale receiver key
+    let inputs = TransferInputs::new(&vm, sender_pk, addr(4), g(8)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let err = contract.transfer_confidential(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::ReceiverPkMismatch(ReceiverPkMismatch { receiver: addr(4) }));
+
+    // Revert data is selector || abi.encode(receiver), decodable by any ABI-aware client
+    let data = Vec::<u8>::from(err);
+    assert_eq!(data[..4], ReceiverPkMismatch::SELECTOR);
+    assert_eq!(ReceiverPkMismatch::abi_decode(&data, true).unwrap().receiver, addr(4));
+}
+
+#[test]
+fn test_transfer_confidential_by_sig_pays_relayer_fee_and_consumes_nonce() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    vm.set_block_timestamp(1_000);
+
+    // account 3 pays receiver 4; relayer 9 submits and takes a confidential fee
+    let (sender_pk, receiver_pk, relayer_pk) = (g(5), g(7), g(9));
+    for (user, pk) in [(addr(4), receiver_pk), (addr(9), relayer_pk), (addr(3), sender_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+
+    let transfer = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk);
+    let fee = TransferInputs {
+        sender_current: transfer.sender_new_balance,
+        sender_version: 1,
+        sender_new_balance: [4u8; 128],
+        receiver_amount: ciphertext(g(1), g(2)),
+        ..TransferInputs::new(&vm, sender_pk, addr(9), relayer_pk)
+    };
+    let (inputs, fee_inputs) = (transfer.encode(), fee.encode());
+    let (proof, fee_proof) = (vec![0xAA; 64], vec![0xBB; 64]);
+    vm.mock_static_call(addr(22), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.mock_static_call(addr(22), verify_calldata(&fee_inputs, &fee_proof), Ok(abi_bool(true)));
+
+    let digest = transfer_intent_digest(&contract, addr(3), (0, &inputs), (0, &fee_inputs), addr(9), 0, 2_000);
+    let signature = mock_signature(&vm, digest, addr(3));
+
+    // Only the named relayer may submit
+    vm.set_sender(addr(8));
+    let err = contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            0,
+            inputs.clone(),
+            proof.clone().into(),
+            0,
+            fee_inputs.clone(),
+            fee_proof.clone().into(),
+            addr(9),
+            U256::from(2_000),
+            signature.clone().into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::RelayerMismatch(RelayerMismatch { relayer: addr(9) }));
+
+    vm.set_sender(addr(9));
+    contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            0,
+            inputs.clone(),
+            proof.clone().into(),
+            0,
+            fee_inputs.clone(),
+            fee_proof.clone().into(),
+            addr(9),
+            U256::from(2_000),
+            signature.clone().into(),
+        )
+        .unwrap();
+    assert_eq!(contract.nonces(addr(3)), U256::from(1));
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), [4u8; 128]);
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), ciphertext(g(1), g(1)));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(9)), ciphertext(g(1), g(2)));
+    let event = last_event::<IntentRelayed>(&vm);
+    assert_eq!((event.account, event.relayer, event.nonce), (addr(3), addr(9), U256::from(0)));
+
+    // The signature was bound to nonce 0
+    let err = contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            0,
+            inputs,
+            proof.into(),
+            0,
+            fee_inputs,
+            fee_proof.into(),
+            addr(9),
+            U256::from(2_000),
+            signature.into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::InvalidSignature(InvalidSignature {}));
+}
+
+#[test]
+fn test_relayed_intents_reject_expired_deadline_foreign_signer_and_other_version() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    vm.set_block_timestamp(1_000);
+
+    let (sender_pk, receiver_pk) = (g(5), g(7));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    let inputs = TransferInputs::new(&vm, sender_pk, addr(4), receiver_pk).encode();
+    let proof = vec![0xAA; 64];
+    vm.set_sender(addr(9));
+
+    let digest = transfer_intent_digest(&contract, addr(3), (0, &inputs), (0, &[]), Address::ZERO, 0, 999);
+    let signature = mock_signature(&vm, digest, addr(3));
+    let err = contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            0,
+            inputs.clone(),
+            proof.clone().into(),
+            0,
+            vec![],
+            vec![].into(),
+            Address::ZERO,
+            U256::from(999),
+            signature.into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::IntentExpired(IntentExpired { deadline: U256::from(999) }));
+
+    // Signed for the current verifier, submitted against another version
+    let digest = transfer_intent_digest(&contract, addr(3), (0, &inputs), (0, &[]), Address::ZERO, 0, 2_000);
+    let signature = mock_signature(&vm, digest, addr(3));
+    let err = contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            1,
+            inputs.clone(),
+            proof.clone().into(),
+            0,
+            vec![],
+            vec![].into(),
+            Address::ZERO,
+            U256::from(2_000),
+            signature.into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::InvalidSignature(InvalidSignature {}));
+
+    // Signed by someone other than the account
+    let digest = transfer_intent_digest(&contract, addr(3), (0, &inputs), (0, &[]), Address::ZERO, 0, 2_000);
+    let signature = mock_signature(&vm, digest, addr(5));
+    let err = contract
+        .transfer_confidential_by_sig(
+            addr(3),
+            0,
+            inputs,
+            proof.into(),
+            0,
+            vec![],
+            vec![].into(),
+            Address::ZERO,
+            U256::from(2_000),
+            signature.into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::InvalidSignature(InvalidSignature {}));
+    assert_eq!(contract.nonces(addr(3)), U256::from(0));
+}
+
+#[test]
+fn test_withdraw_by_sig_fee_respects_transfer_pause() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    vm.set_block_timestamp(1_000);
+    contract.set_operation_paused(OP_TRANSFER, true).unwrap();
+
+    let (pk, relayer_pk) = (g(5), g(9));
+    for (user, pk) in [(addr(9), relayer_pk), (addr(3), pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+    let proof = vec![0xAA; 64];
+    vm.set_sender(addr(9));
+
+    // An unpaid relayed exit still goes through
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(6)).encode();
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let digest = withdraw_intent_digest(&contract, addr(3), addr(6), (0, &inputs), (0, &[]), addr(9), 2_000);
+    let signature = mock_signature(&vm, digest, addr(3));
+    contract
+        .withdraw_by_sig(
+            addr(3),
+            addr(6),
+            0,
+            inputs,
+            proof.clone().into(),
+            0,
+            vec![],
+            vec![].into(),
+            addr(9),
+            U256::from(2_000),
+            signature.into(),
+        )
+        .unwrap();
+
+    // A relayer fee is a transfer proof and is refused while transfers are paused
+    let inputs = DepositWithdrawInputs {
+        current: [2u8; 128],
+        version: 1,
+        new_balance: [3u8; 128],
+        ..DepositWithdrawInputs::new(&vm, pk, addr(6))
+    }
+    .encode();
+    vm.mock_static_call(addr(21), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let fee_inputs = TransferInputs {
+        sender_current: [3u8; 128],
+        sender_version: 2,
+        ..TransferInputs::new(&vm, pk, addr(9), relayer_pk)
+    }
+    .encode();
+    vm.mock_static_call(addr(22), verify_calldata(&fee_inputs, &proof), Ok(abi_bool(true)));
+    let digest = withdraw_intent_digest(&contract, addr(3), addr(6), (0, &inputs), (0, &fee_inputs), addr(9), 2_000);
+    let signature = mock_signature(&vm, digest, addr(3));
+    let err = contract
+        .withdraw_by_sig(
+            addr(3),
+            addr(6),
+            0,
+            inputs,
+            proof.clone().into(),
+            0,
+            fee_inputs,
+            proof.into(),
+            addr(9),
+            U256::from(2_000),
+            signature.into(),
+        )
+        .unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::OperationPaused(OperationPaused { operation: OP_TRANSFER }));
+}
+
+#[test]
+fn test_transfer_confidential_batch_credits_every_receiver() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_batch_transfer_verifier(addr(24)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+
+    let (sender_pk, alice_pk, bob_pk) = (g(5), g(7), g(9));
+    for (user, pk) in [(addr(4), alice_pk), (addr(5), bob_pk), (addr(3), sender_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+
+    // Alice is paid twice in the same batch
+    let inputs = BatchTransferInputs {
+        receivers: vec![
+            (addr(4), alice_pk, ciphertext(g(1), g(1))),
+            (addr(5), bob_pk, ciphertext(g(1), g(2))),
+            (addr(4), alice_pk, ciphertext(g(1), g(1))),
+        ],
+        sender_pk,
+        sender_current: zero_balance(sender_pk),
+        token: weth(),
+        domain: domain(&vm),
+        sender_version: 0,
+        sender_new_balance: [3u8; 128],
+    }
+    .encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.transfer_confidential_batch(0, inputs, proof.into()).unwrap();
+
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), [3u8; 128]);
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), ciphertext(g(2), g(2)));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(5)), ciphertext(g(1), g(2)));
+
+    let event = last_event::<TransferConfidential>(&vm);
+    assert_eq!(event.to, addr(4));
+    assert_eq!(event.sender_balance_version, U256::from(1));
+    assert_eq!(event.receiver_new_pending.as_ref(), ciphertext(g(2), g(2)));
+}
+
+#[test]
+fn test_transfer_confidential_batch_rejects_empty_and_unregistered_receivers() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_batch_transfer_verifier(addr(24)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+
+    let sender_pk = g(5);
+    vm.set_sender(addr(3));
+    contract.register_user_pk(sender_pk).unwrap();
+
+    let batch = |receivers| BatchTransferInputs {
+        receivers,
+        sender_pk,
+        sender_current: zero_balance(sender_pk),
+        token: weth(),
+        domain: domain(&vm),
+        sender_version: 0,
+        sender_new_balance: [3u8; 128],
+    };
+
+    let inputs = batch(vec![]).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential_batch(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::EmptyBatch(EmptyBatch {}));
+
+    // One unknown receiver fails the whole batch
+    let inputs = batch(vec![(addr(4), g(7), ciphertext(g(1), g(1)))]).encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.transfer_confidential_batch(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ReceiverNotRegistered(_)));
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(0));
+}
+
+#[test]
+fn test_apply_pending_merges_into_balance() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    let receiver_pk = g(7);
+    vm.set_sender(addr(4));
+    contract.register_user_pk(receiver_pk).unwrap();
+
+    let err = contract.apply_pending(weth()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NoPendingBalance(_)));
+    assert!(!contract.guard.locked.get());
+
+    contract._add_pending(weth(), addr(4), &contract._decode_ciphertext(ciphertext(g(1), g(2))));
+    // Not callable from inside another guarded call, such as a withdrawal payout
+    contract.guard.locked.set(true);
+    let err = contract.apply_pending(weth()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ReentrantCall(_)));
+    contract.guard.locked.set(false);
+    contract.apply_pending(weth()).unwrap();
+
+    // (G, pk) + (G, 2G) = (2G, pk + 2G)
+    assert_eq!(contract.balance_of_enc(weth(), addr(4)), ciphertext(g(2), g(9)));
+    assert_eq!(contract.balance_version(weth(), addr(4)), U256::from(1));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), [0u8; 128]);
+}
+
+#[test]
+fn test_approve_and_transfer_from_confidential_move_escrowed_allowance() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_allowance_verifiers(addr(24), addr(25)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+    vm.set_code(addr(25), vec![0x00]);
+
+    // owner 3, spender 4, receiver 5
+    let (owner_pk, spender_pk, receiver_pk) = (g(5), g(7), g(9));
+    for (user, pk) in [(addr(3), owner_pk), (addr(4), spender_pk), (addr(5), receiver_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+    assert_eq!(contract.allowance_of_enc(weth(), addr(3), addr(4)), (zero_balance(owner_pk), zero_balance(spender_pk)));
+
+    let approve = ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk);
+    let inputs = approve.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+
+    // The allowance left the owner's balance and is readable by both parties
+    assert_eq!(contract.balance_of_enc(weth(), addr(3)), approve.owner_new_balance);
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+    assert_eq!(contract.allowance_of_enc(weth(), addr(3), addr(4)), (approve.owner_allowance, approve.spender_allowance));
+    assert_eq!(contract.allowance_version(weth(), addr(3), addr(4)), U256::from(1));
+    let event = last_event::<ApprovalConfidential>(&vm);
+    assert_eq!(event.spender, addr(4));
+    assert_eq!(event.allowance_version, U256::from(1));
+
+    let transfer_from = TransferFromInputs::new(&vm, addr(3), owner_pk, addr(5), receiver_pk, spender_pk);
+    let inputs = transfer_from.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(25), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(4));
+    contract.transfer_from_confidential(addr(3), 0, inputs, proof.into()).unwrap();
+
+    // The owner's balance is untouched, the receiver is paid into pending
+    assert_eq!(contract.balance_version(weth(), addr(3)), U256::from(1));
+    assert_eq!(
+        contract.allowance_of_enc(weth(), addr(3), addr(4)),
+        (transfer_from.owner_new_allowance, transfer_from.spender_new_allowance)
+    );
+    assert_eq!(contract.allowance_version(weth(), addr(3), addr(4)), U256::from(2));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(5)), ciphertext(g(1), g(1)));
+    let event = last_event::<TransferFromConfidential>(&vm);
+    assert_eq!(event.to, addr(5));
+    assert_eq!(event.allowance_version, U256::from(2));
+}
+
+#[test]
+fn test_rotate_user_pk_waits_only_for_granted_allowances() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_allowance_verifiers(addr(24), addr(25)).unwrap();
+    contract.set_rekey_verifier(addr(23)).unwrap();
+    execute_pending(&vm, &mut contract);
+    for verifier in [addr(23), addr(24)] {
+        vm.set_code(verifier, vec![0x00]);
+    }
+
+    // A third party names user 4 as spender without asking
+    let (owner_pk, spender_pk) = (g(5), g(7));
+    for (user, pk) in [(addr(3), owner_pk), (addr(4), spender_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+    let approve = ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk);
+    let inputs = approve.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::from(1));
+    assert_eq!(contract.open_allowance_count(addr(4)), U256::ZERO);
+
+    // The spender still rotates
+    vm.set_sender(addr(4));
+    let inputs = rekey_inputs(&vm, spender_pk, g(11), addr(4)).encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.rotate_user_pk(g(11), 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.get_user_pk(addr(4)), g(11));
+
+    // The owner's escrow is under the owner's key, which can't change under it
+    let mut owner_rekey = rekey_inputs(&vm, owner_pk, g(13), addr(3));
+    owner_rekey.tokens[0] = weth();
+    owner_rekey.versions[0] = 1;
+    owner_rekey.old_balances[0] = approve.owner_new_balance;
+    owner_rekey.new_balances[0] = [8u8; 128];
+    let inputs = owner_rekey.encode();
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    let err = contract.rotate_user_pk(g(13), 0, inputs.clone(), proof.clone().into()).unwrap_err();
+    let open = OpenAllowances { user: addr(3), count: U256::from(1) };
+    assert_eq!(err, ConfidentialERC20Error::OpenAllowances(open));
+
+    // The spender drops it and the escrow goes back to the owner
+    vm.set_sender(addr(4));
+    let err = contract.drop_allowance(weth(), addr(5)).unwrap_err();
+    let missing = NoAllowance { token: weth(), owner: addr(5), spender: addr(4) };
+    assert_eq!(err, ConfidentialERC20Error::NoAllowance(missing));
+    contract.drop_allowance(weth(), addr(3)).unwrap();
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(3)), approve.owner_allowance);
+    assert_eq!(contract.allowance_of_enc(weth(), addr(3), addr(4)), (zero_balance(owner_pk), zero_balance(g(11))));
+    assert_eq!(contract.allowance_version(weth(), addr(3), addr(4)), U256::from(2));
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::ZERO);
+    let event = last_event::<AllowanceDropped>(&vm);
+    assert_eq!((event.owner, event.spender), (addr(3), addr(4)));
+    assert_eq!(event.owner_new_pending.as_ref(), approve.owner_allowance);
+    let err = contract.drop_allowance(weth(), addr(3)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::NoAllowance(_)));
+
+    vm.set_sender(addr(3));
+    contract.rotate_user_pk(g(13), 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.get_user_pk(addr(3)), g(13));
+}
+
+#[test]
+fn test_allowances_close_on_proven_zero() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_allowance_verifiers(addr(24), addr(25)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+    vm.set_code(addr(25), vec![0x00]);
+
+    let (owner_pk, spender_pk, receiver_pk) = (g(5), g(7), g(9));
+    for (user, pk) in [(addr(3), owner_pk), (addr(4), spender_pk), (addr(5), receiver_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+    let approve = ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk);
+    let inputs = approve.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::from(1));
+
+    // Spending all of it leaves a homomorphic encryption of 0, not the canonical one
+    let spend_all = TransferFromInputs {
+        allowance_open: false,
+        ..TransferFromInputs::new(&vm, addr(3), owner_pk, addr(5), receiver_pk, spender_pk)
+    };
+    let inputs = spend_all.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(25), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(4));
+    contract.transfer_from_confidential(addr(3), 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.allowance_of_enc(weth(), addr(3), addr(4)).0, spend_all.owner_new_allowance);
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::ZERO);
+
+    // Approving again reopens it, and approving 0 with any randomness closes it
+    let reapprove = ApproveInputs {
+        owner_current: approve.owner_new_balance,
+        balance_version: 1,
+        allowance_version: 2,
+        owner_current_allowance: spend_all.owner_new_allowance,
+        ..ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk)
+    };
+    let inputs = reapprove.encode();
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::from(1));
+
+    let revoke = ApproveInputs {
+        owner_current: reapprove.owner_new_balance,
+        balance_version: 2,
+        allowance_version: 3,
+        owner_current_allowance: reapprove.owner_allowance,
+        owner_new_balance: [6u8; 128],
+        owner_allowance: ciphertext(g(4), g(6)),
+        spender_allowance: ciphertext(g(4), g(8)),
+        allowance_open: false,
+        ..ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk)
+    };
+    let inputs = revoke.encode();
+    let proof = vec![0xDD; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::ZERO);
+}
+
+#[test]
+fn test_auditor_key_requires_no_open_allowances() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_allowance_verifiers(addr(24), addr(25)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+
+    let (owner_pk, spender_pk) = (g(5), g(7));
+    for (user, pk) in [(addr(3), owner_pk), (addr(4), spender_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+    let approve = ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk);
+    let inputs = approve.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+
+    // An auditor key would strand the open allowance, both when queued...
+    vm.set_sender(addr(1));
+    let err = contract.set_auditor_key(weth(), g(11)).unwrap_err();
+    let open = TokenHasOpenAllowances { token: weth(), count: U256::from(1) };
+    assert_eq!(err, ConfidentialERC20Error::TokenHasOpenAllowances(open.clone()));
+    assert_eq!(contract.token_open_allowance_count(weth()), U256::from(1));
+
+    // ...and when executed, if the allowance was opened while the change was queued
+    contract._set_allowance(weth(), addr(3), addr(4), &Ciphertext::zero(), &Ciphertext::zero(), false);
+    contract.set_auditor_key(weth(), g(11)).unwrap();
+    contract._set_allowance(weth(), addr(3), addr(4), &Ciphertext::zero(), &Ciphertext::zero(), true);
+    let (id, .., eta) = contract.pending_changes()[0];
+    vm.set_block_timestamp(eta.to::<u64>());
+    let err = contract.execute_change(id).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::TokenHasOpenAllowances(open));
+    assert_eq!(contract.get_auditor_key(weth()), [0u8; 64]);
+
+    // Once the allowance is revoked the auditor can be set
+    let revoke = ApproveInputs {
+        owner_current: approve.owner_new_balance,
+        balance_version: 1,
+        allowance_version: 3,
+        owner_current_allowance: [0u8; 128],
+        owner_allowance: zero_balance(owner_pk),
+        spender_allowance: zero_balance(spender_pk),
+        allowance_open: false,
+        ..ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk)
+    };
+    let inputs = revoke.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.token_open_allowance_count(weth()), U256::ZERO);
+    vm.set_sender(addr(1));
+    contract.set_auditor_key(weth(), g(11)).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_auditor_key(weth()), g(11));
+
+    // A new allowance could never be spent by transferFromConfidential
+    let raise = ApproveInputs {
+        owner_current: revoke.owner_new_balance,
+        balance_version: 2,
+        allowance_version: 4,
+        owner_current_allowance: zero_balance(owner_pk),
+        ..ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk)
+    };
+    let inputs = raise.encode();
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    let err = contract.approve_confidential(0, inputs, proof.into()).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::AuditedToken(AuditedToken { token: weth() }));
+    assert_eq!(contract.open_allowance_count(addr(3)), U256::ZERO);
+}
+
+#[test]
+fn test_transfer_from_confidential_rejects_missing_and_stale_allowance() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_allowance_verifiers(addr(24), addr(25)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(24), vec![0x00]);
+    vm.set_code(addr(25), vec![0x00]);
+
+    let (owner_pk, spender_pk, receiver_pk) = (g(5), g(7), g(9));
+    for (user, pk) in [(addr(3), owner_pk), (addr(4), spender_pk), (addr(5), receiver_pk)] {
+        vm.set_sender(user);
+        contract.register_user_pk(pk).unwrap();
+    }
+
+    let transfer_from = TransferFromInputs::new(&vm, addr(3), owner_pk, addr(5), receiver_pk, spender_pk);
+    let inputs = transfer_from.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(25), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(4));
+    let err = contract.transfer_from_confidential(addr(3), 0, inputs, proof.into()).unwrap_err();
+    assert_eq!(
+        err,
+        ConfidentialERC20Error::NoAllowance(NoAllowance { token: weth(), owner: addr(3), spender: addr(4) })
+    );
+
+    let inputs = ApproveInputs::new(&vm, owner_pk, addr(4), spender_pk).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(24), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(3));
+    contract.approve_confidential(0, inputs, proof.into()).unwrap();
+
+    // Proof built against a different allowance ciphertext
+    let inputs = TransferFromInputs { spender_current_allowance: [9u8; 128], ..transfer_from }.encode();
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(25), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(4));
+    let err = contract.transfer_from_confidential(addr(3), 0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::StaleAllowance(_)));
+}
+
+fn rekey_inputs(vm: &TestVM, old_pk: [u8; 64], new_pk: [u8; 64], user: Address) -> RekeyInputs {
+    RekeyInputs {
+        old_pk,
+        new_pk,
+        user,
+        domain: domain(vm),
+        tokens: [Address::ZERO; REKEY_SLOTS],
+        versions: [0; REKEY_SLOTS],
+        old_balances: [[0u8; 128]; REKEY_SLOTS],
+        new_balances: [[0u8; 128]; REKEY_SLOTS],
+    }
+}
+
+#[test]
+fn test_rotate_user_pk_reencrypts_balance_and_keeps_pending_apart() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_rekey_verifier(addr(23)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(23), vec![0x00]);
+
+    let old_pk = g(7);
+    let new_pk = g(11);
+    vm.set_sender(addr(4));
+    contract.register_user_pk(old_pk).unwrap();
+    contract._add_pending(weth(), addr(4), &contract._decode_ciphertext(ciphertext(g(1), g(2))));
+
+    // Only the balance is re-encrypted, so incoming transfers can't make the proof stale
+    let new_balance = ciphertext(g(3), g(5));
+    let mut inputs = rekey_inputs(&vm, old_pk, new_pk, addr(4));
+    inputs.tokens[0] = weth();
+    inputs.old_balances[0] = zero_balance(old_pk);
+    inputs.new_balances[0] = new_balance;
+    let inputs = inputs.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    contract.rotate_user_pk(new_pk, 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.get_user_pk(addr(4)), new_pk);
+    assert_eq!(contract.get_user_pk_at(addr(4), U256::ZERO), old_pk);
+    assert_eq!(contract.key_epoch(addr(4)), U256::from(1));
+    assert_eq!(contract.balance_of_enc(weth(), addr(4)), new_balance);
+    assert_eq!(contract.balance_version(weth(), addr(4)), U256::from(1));
+    assert_eq!(contract.balance_key_epoch(weth(), addr(4)), U256::from(1));
+    assert_eq!(last_event::<UserPkRotated>(&vm).key_epoch, U256::from(1));
+
+    // Transfers pending under the old key stay in their own bucket
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), [0u8; 128]);
+    contract._add_pending(weth(), addr(4), &contract._decode_ciphertext(ciphertext(g(4), g(6))));
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), ciphertext(g(4), g(6)));
+    assert_eq!(contract.pending_balance_at_epoch(weth(), addr(4), U256::ZERO), ciphertext(g(1), g(2)));
+
+    // ...until they are re-encrypted and merged into the pending balance
+    let mut inputs = rekey_inputs(&vm, old_pk, new_pk, addr(4));
+    inputs.tokens[0] = weth();
+    inputs.old_balances[0] = ciphertext(g(1), g(2));
+    inputs.new_balances[0] = ciphertext(g(1), g(1));
+    let inputs = inputs.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.rekey_pending(0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.pending_balance_at_epoch(weth(), addr(4), U256::ZERO), [0u8; 128]);
+    assert_eq!(contract.pending_balance_of_enc(weth(), addr(4)), ciphertext(g(5), g(7)));
+    let event = last_event::<PendingReencrypted>(&vm);
+    assert_eq!((event.key_epoch, event.new_pending.as_ref()), (U256::ZERO, ciphertext(g(5), g(7)).as_slice()));
+
+    contract.apply_pending(weth()).unwrap();
+    assert_eq!(contract.balance_of_enc(weth(), addr(4)), ciphertext(g(8), g(12)));
+}
+
+#[test]
+fn test_rotate_user_pk_must_rekey_every_balance() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_rekey_verifier(addr(23)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(23), vec![0x00]);
+    let token = addr(50);
+    contract.set_token_config(token, U256::from(1), 6).unwrap();
+    contract.set_supported_token(token, true).unwrap();
+
+    let (old_pk, new_pk) = (g(7), g(11));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(old_pk).unwrap();
+    contract._set_balance(token, addr(4), &contract._decode_ciphertext(ciphertext(g(2), g(3))));
+
+    // A proof that leaves a held balance out would strand it under the retired key
+    let mut rekey = rekey_inputs(&vm, old_pk, new_pk, addr(4));
+    rekey.tokens[0] = weth();
+    rekey.old_balances[0] = zero_balance(old_pk);
+    let inputs = rekey.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.rotate_user_pk(new_pk, 0, inputs, proof.into()).unwrap_err();
+    let incomplete = RekeyIncomplete { user: addr(4), token };
+    assert_eq!(err, ConfidentialERC20Error::RekeyIncomplete(incomplete));
+    assert_eq!(contract.get_user_pk(addr(4)), old_pk);
+
+    // Each token takes a single slot
+    rekey.tokens[0] = token;
+    rekey.tokens[1] = token;
+    rekey.versions[0] = 1;
+    rekey.versions[1] = 1;
+    rekey.old_balances[0] = ciphertext(g(2), g(3));
+    rekey.old_balances[1] = ciphertext(g(2), g(3));
+    let inputs = rekey.encode();
+    let proof = vec![0xBB; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    let err = contract.rotate_user_pk(new_pk, 0, inputs, proof.into()).unwrap_err();
+    let repeated = RekeyTokenMismatch { slot: U256::from(1), token };
+    assert_eq!(err, ConfidentialERC20Error::RekeyTokenMismatch(repeated));
+
+    rekey.tokens[1] = Address::ZERO;
+    rekey.new_balances[0] = ciphertext(g(5), g(9));
+    let inputs = rekey.encode();
+    let proof = vec![0xCC; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.rotate_user_pk(new_pk, 0, inputs, proof.into()).unwrap();
+    assert_eq!(contract.balance_of_enc(token, addr(4)), ciphertext(g(5), g(9)));
+    assert_eq!(contract.balance_key_epoch(token, addr(4)), U256::from(1));
+    let current = contract._decode_ciphertext(ciphertext(g(5), g(9)));
+    assert!(contract._verify_current_amount(token, addr(4), &current));
+}
+
+#[test]
+fn test_rotate_user_pk_refuses_more_balances_than_rekey_slots() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+    contract.set_rekey_verifier(addr(23)).unwrap();
+    execute_pending(&vm, &mut contract);
+    vm.set_code(addr(23), vec![0x00]);
+
+    let (old_pk, new_pk) = (g(7), g(11));
+    vm.set_sender(addr(4));
+    contract.register_user_pk(old_pk).unwrap();
+    vm.set_sender(addr(1));
+    for n in 1..=REKEY_SLOTS {
+        let token = addr(50 + n as u8);
+        contract.set_token_config(token, U256::from(1), 6).unwrap();
+        contract._set_balance(token, addr(4), &contract._decode_ciphertext(ciphertext(g(2), g(3))));
+    }
+    contract._set_balance(weth(), addr(4), &contract._decode_ciphertext(ciphertext(g(2), g(3))));
+
+    let mut rekey = rekey_inputs(&vm, old_pk, new_pk, addr(4));
+    for n in 1..=REKEY_SLOTS {
+        rekey.tokens[n - 1] = addr(50 + n as u8);
+        rekey.versions[n - 1] = 1;
+        rekey.old_balances[n - 1] = ciphertext(g(2), g(3));
+    }
+    let inputs = rekey.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(23), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    vm.set_sender(addr(4));
+    let err = contract.rotate_user_pk(new_pk, 0, inputs, proof.into()).unwrap_err();
+    let too_many = TooManyRekeyBalances { user: addr(4), count: U256::from(REKEY_SLOTS + 1) };
+    assert_eq!(err, ConfidentialERC20Error::TooManyRekeyBalances(too_many));
+    assert_eq!(contract.key_epoch(addr(4)), U256::ZERO);
+}
+
+#[test]
+fn test_token_list_is_not_bounded_by_rekey_slots() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    for n in 1..=2 * REKEY_SLOTS {
+        contract.set_token_config(addr(50 + n as u8), U256::from(1), 6).unwrap();
+    }
+    assert_eq!(contract.get_token_list().len(), 2 * REKEY_SLOTS + 1);
+
+    // Reconfiguring a listed token does not list it again
+    contract.set_token_config(weth(), U256::from(10), 18).unwrap();
+    execute_pending(&vm, &mut contract);
+    assert_eq!(contract.get_token_list().len(), 2 * REKEY_SLOTS + 1);
+}
+
+#[test]
+fn test_deposit_uses_token_scale_factor() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    // USDC-like token: 6 decimals, proof amounts are already in token units
+    let usdc = addr(50);
+    vm.set_sender(addr(1));
+    contract.set_token_config(usdc, U256::from(1), 6).unwrap();
+    contract.set_supported_token(usdc, true).unwrap();
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+
+    let inputs = DepositWithdrawInputs { token: usdc, ..DepositWithdrawInputs::new(&vm, pk, addr(3)) }.encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+
+    let transfer_from = transferFromCall {
+        from: addr(3),
+        to: vm.contract_address(),
+        amount: U256::from(10),
+    }
+    .abi_encode();
+    vm.mock_call(usdc, transfer_from, Ok(abi_bool(false)));
+
+    // The mocked transferFrom only matches calldata with the unscaled amount
+    let err = contract.deposit(0, inputs, proof.into()).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::Erc20TransferFromFailed(_)));
+}
+
+#[test]
+fn test_scale_factor_is_locked_once_token_holds_deposits() {
+    let vm = TestVM::default();
+    let mut contract = setup(&vm);
+
+    // A reconfiguration is queued, and rechecked when it executes
+    contract.set_token_config(weth(), U256::from(1), WETH_DECIMALS).unwrap();
+    assert_eq!(contract.get_token_config(weth()), (true, U256::from(DEFAULT_SCALE_FACTOR), WETH_DECIMALS));
+    let queued = contract.pending_changes()[0];
+    assert_eq!((queued.1, queued.2, queued.3), (CHANGE_TOKEN_CONFIG, weth(), U256::from(1)));
+
+    vm.set_sender(addr(3));
+    let pk = [0x11; 64];
+    contract.register_user_pk(pk).unwrap();
+    let inputs = DepositWithdrawInputs::new(&vm, pk, addr(3)).encode();
+    let proof = vec![0xAA; 64];
+    vm.mock_static_call(addr(20), verify_calldata(&inputs, &proof), Ok(abi_bool(true)));
+    contract.deposit(0, inputs, proof.into()).unwrap();
+
+    vm.set_sender(addr(1));
+    vm.set_block_timestamp(queued.6.to::<u64>());
+    let err = contract.execute_change(queued.0).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::ScaleFactorLocked(ScaleFactorLocked { token: weth() }));
+
+    // Repricing would change the value of every encrypted balance; decimals can still change
+    let err = contract.set_token_config(weth(), U256::from(1), WETH_DECIMALS).unwrap_err();
+    assert_eq!(err, ConfidentialERC20Error::ScaleFactorLocked(ScaleFactorLocked { token: weth() }));
+    contract.set_token_config(weth(), U256::from(DEFAULT_SCALE_FACTOR), 8).unwrap();
+    let err = contract.execute_change(queued.0 + U256::from(1)).unwrap_err();
+    assert!(matches!(err, ConfidentialERC20Error::ChangeNotReady(_)));
+    vm.set_block_timestamp(2 * queued.6.to::<u64>());
+    contract.execute_change(queued.0 + U256::from(1)).unwrap();
+    assert_eq!(contract.get_token_config(weth()), (true, U256::from(DEFAULT_SCALE_FACTOR), 8));
 }
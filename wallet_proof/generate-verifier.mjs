@@ -42,6 +42,26 @@ async function generateAllVerifiers() {
       name: 'Transfer',
       path: './target/transfer.json',
       output: './contracts/TransferVerifier.sol'
+    },
+    {
+      name: 'BatchTransfer',
+      path: './target/batch_transfer.json',
+      output: './contracts/BatchTransferVerifier.sol'
+    },
+    {
+      name: 'Approve',
+      path: './target/approve.json',
+      output: './contracts/ApproveVerifier.sol'
+    },
+    {
+      name: 'TransferFrom',
+      path: './target/transfer_from.json',
+      output: './contracts/TransferFromVerifier.sol'
+    },
+    {
+      name: 'Rekey',
+      path: './target/rekey.json',
+      output: './contracts/RekeyVerifier.sol'
     }
   ];
 